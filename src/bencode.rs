@@ -1,19 +1,242 @@
-use serde::{Deserialize, Serialize};
-use serde_bytes::ByteBuf;
+//! A bencode document model for inspecting and patching values without a
+//! struct for every field.
+
+use crate::mini_serde_bencode::{self, from_bytes, to_bytes, BigInteger, Path, PathSegment};
+use serde::{
+    de::{self, DeserializeOwned, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_bytes::{ByteBuf, Bytes};
 use std::{collections::BTreeMap, fmt::Display};
 
-type Dict = BTreeMap<ByteBuf, BencodeValue>;
+pub type Dict = BTreeMap<ByteBuf, BencodeValue>;
+
+/// Returned when inserting a key into a value that isn't a dictionary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("the bencode value is not a dictionary")]
+pub struct NotADictionary;
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Serialize, Eq, PartialEq)]
 #[serde(untagged)]
 pub enum BencodeValue {
     List(Vec<BencodeValue>),
     #[serde(with = "serde_bytes")]
     String(Vec<u8>),
     Integer(i64),
+    /// An integer that does not fit in an `i64`.
+    BigInteger(BigInteger),
     Dictionary(Dict),
 }
 
+impl BencodeValue {
+    /// Starts building a dictionary.
+    pub fn dict() -> DictBuilder {
+        DictBuilder::default()
+    }
+
+    /// Converts any serializable value, e.g. a `Torrent`, into a document.
+    pub fn from_serialize<T: Serialize>(value: &T) -> mini_serde_bencode::Result<Self> {
+        from_bytes(&to_bytes(value)?)
+    }
+
+    /// Converts the document into any deserializable type.
+    pub fn deserialize_into<T: DeserializeOwned>(&self) -> mini_serde_bencode::Result<T> {
+        from_bytes(&to_bytes(self)?)
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            BencodeValue::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BencodeValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The byte string, if it is valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|s| std::str::from_utf8(s).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeValue]> {
+        match self {
+            BencodeValue::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_list_mut(&mut self) -> Option<&mut Vec<BencodeValue>> {
+        match self {
+            BencodeValue::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&Dict> {
+        match self {
+            BencodeValue::Dictionary(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict_mut(&mut self) -> Option<&mut Dict> {
+        match self {
+            BencodeValue::Dictionary(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// Looks up `key` if this is a dictionary.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&BencodeValue> {
+        self.as_dict()?.get(Bytes::new(key.as_ref()))
+    }
+
+    pub fn get_mut(&mut self, key: impl AsRef<[u8]>) -> Option<&mut BencodeValue> {
+        self.as_dict_mut()?.get_mut(Bytes::new(key.as_ref()))
+    }
+
+    /// Follows a path such as `info.files[0].length`, returning `None` if the
+    /// path is malformed or leads nowhere.
+    pub fn get_path(&self, path: &str) -> Option<&BencodeValue> {
+        let path = path.parse::<Path>().ok()?;
+        path.0
+            .iter()
+            .try_fold(self, |value, segment| match segment {
+                PathSegment::Key(key) => value.get(key),
+                PathSegment::Index(index) => value.as_list()?.get(*index),
+            })
+    }
+
+    pub fn get_path_mut(&mut self, path: &str) -> Option<&mut BencodeValue> {
+        let path = path.parse::<Path>().ok()?;
+        path.0
+            .iter()
+            .try_fold(self, |value, segment| match segment {
+                PathSegment::Key(key) => value.get_mut(key),
+                PathSegment::Index(index) => value.as_list_mut()?.get_mut(*index),
+            })
+    }
+
+    /// Sets `key` if this is a dictionary, returning the previous value.
+    pub fn insert(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<BencodeValue>,
+    ) -> Result<Option<BencodeValue>, NotADictionary> {
+        let dict = self.as_dict_mut().ok_or(NotADictionary)?;
+        Ok(dict.insert(ByteBuf::from(key.into()), value.into()))
+    }
+
+    /// Removes `key` if this is a dictionary.
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Option<BencodeValue> {
+        self.as_dict_mut()?.remove(Bytes::new(key.as_ref()))
+    }
+}
+
+/// Builds a [`BencodeValue::Dictionary`] one entry at a time.
+#[derive(Debug, Default)]
+pub struct DictBuilder(Dict);
+
+impl DictBuilder {
+    pub fn entry(mut self, key: impl Into<Vec<u8>>, value: impl Into<BencodeValue>) -> Self {
+        self.0.insert(ByteBuf::from(key.into()), value.into());
+        self
+    }
+
+    pub fn build(self) -> BencodeValue {
+        BencodeValue::Dictionary(self.0)
+    }
+}
+
+// Written by hand rather than derived as untagged: serde buffers untagged
+// input in a form that cannot hold 128-bit or larger integers.
+impl<'de> Deserialize<'de> for BencodeValue {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ValueVisitor;
+
+        impl<'de> Visitor<'de> for ValueVisitor {
+            type Value = BencodeValue;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a bencode value")
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<BencodeValue, E> {
+                Ok(BencodeValue::Integer(v))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<BencodeValue, E> {
+                Ok(i64::try_from(v).map_or_else(
+                    |_| BencodeValue::BigInteger(v.into()),
+                    BencodeValue::Integer,
+                ))
+            }
+
+            fn visit_i128<E: de::Error>(self, v: i128) -> Result<BencodeValue, E> {
+                Ok(BencodeValue::BigInteger(v.into()))
+            }
+
+            fn visit_u128<E: de::Error>(self, v: u128) -> Result<BencodeValue, E> {
+                Ok(BencodeValue::BigInteger(v.into()))
+            }
+
+            fn visit_newtype_struct<D>(self, deserializer: D) -> Result<BencodeValue, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                let digits = String::deserialize(deserializer)?;
+                BigInteger::new(digits)
+                    .map(BencodeValue::BigInteger)
+                    .map_err(de::Error::custom)
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<BencodeValue, E> {
+                Ok(BencodeValue::String(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<BencodeValue, E> {
+                Ok(BencodeValue::String(v))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<BencodeValue, E> {
+                Ok(BencodeValue::String(v.as_bytes().to_vec()))
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<BencodeValue, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut list = Vec::new();
+                while let Some(value) = seq.next_element()? {
+                    list.push(value);
+                }
+                Ok(BencodeValue::List(list))
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<BencodeValue, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let mut dict = Dict::new();
+                while let Some((key, value)) = map.next_entry()? {
+                    dict.insert(key, value);
+                }
+                Ok(BencodeValue::Dictionary(dict))
+            }
+        }
+
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
 impl Display for BencodeValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -22,6 +245,7 @@ impl Display for BencodeValue {
                 write!(f, "\"{s}\"")
             }
             BencodeValue::Integer(i) => write!(f, "{i}"),
+            BencodeValue::BigInteger(i) => write!(f, "{i}"),
             BencodeValue::List(l) => {
                 write!(f, "[")?;
                 for (i, value) in l.iter().enumerate() {
@@ -49,15 +273,98 @@ impl Display for BencodeValue {
     }
 }
 
+macro_rules! impl_from_int {
+    ($($ty:ty)*) => {
+        $(
+            impl From<$ty> for BencodeValue {
+                fn from(i: $ty) -> Self {
+                    BencodeValue::Integer(i.into())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_int!(i8 i16 i32 i64 u8 u16 u32);
+
+impl From<u64> for BencodeValue {
+    fn from(i: u64) -> Self {
+        i64::try_from(i).map_or_else(
+            |_| BencodeValue::BigInteger(i.into()),
+            BencodeValue::Integer,
+        )
+    }
+}
+
+impl From<usize> for BencodeValue {
+    fn from(i: usize) -> Self {
+        (i as u64).into()
+    }
+}
+
+impl From<BigInteger> for BencodeValue {
+    fn from(i: BigInteger) -> Self {
+        BencodeValue::BigInteger(i)
+    }
+}
+
+impl From<&str> for BencodeValue {
+    fn from(s: &str) -> Self {
+        BencodeValue::String(s.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for BencodeValue {
+    fn from(s: String) -> Self {
+        BencodeValue::String(s.into_bytes())
+    }
+}
+
+impl From<&[u8]> for BencodeValue {
+    fn from(s: &[u8]) -> Self {
+        BencodeValue::String(s.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for BencodeValue {
+    fn from(s: Vec<u8>) -> Self {
+        BencodeValue::String(s)
+    }
+}
+
+impl From<ByteBuf> for BencodeValue {
+    fn from(s: ByteBuf) -> Self {
+        BencodeValue::String(s.into_vec())
+    }
+}
+
+impl From<Vec<BencodeValue>> for BencodeValue {
+    fn from(l: Vec<BencodeValue>) -> Self {
+        BencodeValue::List(l)
+    }
+}
+
+impl From<Dict> for BencodeValue {
+    fn from(d: Dict) -> Self {
+        BencodeValue::Dictionary(d)
+    }
+}
+
+impl<T: Into<BencodeValue>> FromIterator<T> for BencodeValue {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        BencodeValue::List(iter.into_iter().map(Into::into).collect())
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 #[cfg(test)]
 mod tests {
+    use serde::Deserialize;
     use serde_bytes::ByteBuf;
 
-    use crate::bencode::Dict;
+    use super::*;
     use crate::mini_serde_bencode::{from_str, to_string};
-    use crate::BencodeValue;
 
     #[test]
     fn test_ser() {
@@ -116,4 +423,76 @@ mod tests {
         let expected = BencodeValue::Dictionary(dict);
         assert_eq!(d, expected);
     }
+
+    #[test]
+    fn test_big_integer() {
+        let input = "li9223372036854775808ei-170141183460469231731687303715884105729ee";
+        let value = from_str::<BencodeValue>(input).unwrap();
+        let BencodeValue::List(l) = &value else {
+            panic!("expected a list, got {value}");
+        };
+        assert!(
+            matches!(&l[0], BencodeValue::BigInteger(i) if i.as_str() == "9223372036854775808")
+        );
+        assert!(matches!(
+            &l[1],
+            BencodeValue::BigInteger(i) if i.as_str() == "-170141183460469231731687303715884105729"
+        ));
+        assert_eq!(to_string(&value).unwrap(), input);
+    }
+
+    #[test]
+    fn test_get_path() {
+        let input = "d4:infod5:filesld6:lengthi5e4:pathl4:spameeee4:name3:egge";
+        let value = from_str::<BencodeValue>(input).unwrap();
+        let length = value.get_path("info.files[0].length");
+        assert_eq!(length.and_then(BencodeValue::as_int), Some(5));
+        let path = value.get_path("info.files[0].path[0]");
+        assert_eq!(path.and_then(BencodeValue::as_str), Some("spam"));
+        assert!(value.get_path("info.files[1]").is_none());
+        assert!(value.get_path("info.files.length").is_none());
+        assert!(value.get_path("info..files").is_none());
+    }
+
+    #[test]
+    fn test_mutation() {
+        let mut value = BencodeValue::dict()
+            .entry("announce", "http://tracker")
+            .entry("info", BencodeValue::dict().entry("length", 5u64).build())
+            .build();
+        *value.get_path_mut("info.length").unwrap() = 6.into();
+        assert_eq!(value.insert("comment", "hi"), Ok(None));
+        assert!(value.remove("announce").is_some());
+        assert_eq!(
+            BencodeValue::from(5).insert("comment", "hi"),
+            Err(NotADictionary)
+        );
+        assert_eq!(
+            to_string(&value).unwrap(),
+            "d7:comment2:hi4:infod6:lengthi6eee"
+        );
+    }
+
+    #[test]
+    fn test_conversion() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Info {
+            name: String,
+            length: u64,
+        }
+
+        let info = Info {
+            name: "spam".to_string(),
+            length: 5,
+        };
+        let value = BencodeValue::from_serialize(&info).unwrap();
+        assert_eq!(
+            value.get("name").and_then(BencodeValue::as_str),
+            Some("spam")
+        );
+        assert_eq!(value.deserialize_into::<Info>().unwrap(), info);
+
+        let list: BencodeValue = [1, 2].into_iter().collect();
+        assert_eq!(to_string(&list).unwrap(), "li1ei2ee");
+    }
 }
@@ -1,23 +1,77 @@
-use clap::{Parser, Subcommand};
+use clap::{Args as ClapArgs, Parser, Subcommand, ValueEnum};
+use std::net::IpAddr;
 
 #[derive(Parser)]
 #[command(author = "Tahos81, tahirozpala@gmail.com", version, about, long_about = None)]
 pub struct Args {
     #[command(subcommand)]
     pub cmd: Commands,
+    #[command(flatten)]
+    pub identity: IdentityArgs,
+}
+
+/// How the client presents itself to trackers and peers.
+#[derive(ClapArgs, Debug, Clone, Copy)]
+pub struct IdentityArgs {
+    /// Peer id of exactly 20 bytes; random by default.
+    #[clap(long, global = true, value_parser = parse_peer_id)]
+    pub peer_id: Option<[u8; 20]>,
+    /// Port to tell trackers we listen on.
+    #[clap(long, global = true, default_value_t = 6881)]
+    pub port: u16,
+    /// Tracker key as up to 8 hex digits; random by default.
+    #[clap(long, global = true, value_parser = parse_key)]
+    pub key: Option<u32>,
+    /// Number of peers to ask trackers for.
+    #[clap(long, global = true)]
+    pub numwant: Option<u32>,
+    /// Address to announce, for when trackers can't tell it themselves.
+    #[clap(long = "ip", id = "announce_ip", global = true)]
+    pub ip: Option<IpAddr>,
+}
+
+fn parse_peer_id(peer_id: &str) -> Result<[u8; 20], String> {
+    peer_id
+        .as_bytes()
+        .try_into()
+        .map_err(|_| format!("must be 20 bytes, not {}", peer_id.len()))
+}
+
+fn parse_key(key: &str) -> Result<u32, String> {
+    u32::from_str_radix(key, 16).map_err(|e| e.to_string())
 }
 
 #[derive(Subcommand, Debug, Clone)]
 pub enum Commands {
     Decode {
         value: String,
+        #[clap(long, value_enum, default_value_t = DecodeFormat::Display)]
+        format: DecodeFormat,
+    },
+    /// Turns JSON in the form `decode --format json` prints back into bencode.
+    Encode {
+        #[clap(short)]
+        output_file: Option<String>,
+        json: String,
     },
     Info {
         torrent_file: String,
     },
+    /// Checks a torrent file for problems such as a piece count that
+    /// doesn't match its length or paths that escape the download directory.
+    #[clap(name = "verify-meta")]
+    VerifyMeta {
+        torrent_file: String,
+    },
     Peers {
         torrent_file: String,
     },
+    /// Asks the trackers how many peers have and are downloading each
+    /// torrent, scraping torrents that share trackers in one request.
+    Scrape {
+        #[clap(required = true)]
+        torrent_files: Vec<String>,
+    },
     Handshake {
         torrent_file: String,
         ip: Option<String>,
@@ -29,9 +83,110 @@ pub enum Commands {
         torrent_file: String,
         piece_index: u32,
     },
+    /// Creates a torrent file for a local file or directory.
+    Create {
+        #[clap(short)]
+        output_file: String,
+        path: String,
+        /// Tracker URL; repeat to add more, each in its own tier. Without
+        /// one the torrent is trackerless.
+        #[clap(long = "tracker")]
+        trackers: Vec<String>,
+        /// Web seed URL; may be repeated.
+        #[clap(long = "web-seed")]
+        web_seeds: Vec<String>,
+        #[clap(long)]
+        comment: Option<String>,
+        #[clap(long)]
+        private: bool,
+        /// Piece length in bytes, picked from the content size by default.
+        #[clap(long)]
+        piece_length: Option<u32>,
+    },
+    /// Rewrites the trackers, web seeds, comment, private flag or source of
+    /// a torrent file. Only the last two change the info hash.
+    Edit(EditArgs),
+    #[clap(name = "magnet_parse")]
+    MagnetParse {
+        magnet_link: String,
+    },
+    /// Fetches the info dictionary of a magnet link from peers and prints it
+    /// like `info`.
+    #[clap(name = "magnet_info")]
+    MagnetInfo {
+        magnet_link: String,
+    },
+    #[clap(name = "magnet_download")]
+    MagnetDownload {
+        #[clap(short)]
+        output_file: String,
+        magnet_link: String,
+    },
+    /// Downloads the whole torrent. Multi-file torrents are written to a
+    /// directory named after the torrent inside the output directory.
     Download {
         #[clap(short)]
         output_file: String,
         torrent_file: String,
     },
 }
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeFormat {
+    /// Human-readable, with binary strings shown lossily.
+    Display,
+    /// JSON that `encode` can turn back into the same bencode.
+    Json,
+}
+
+#[derive(ClapArgs, Debug, Clone)]
+pub struct EditArgs {
+    #[clap(short)]
+    pub output_file: String,
+    pub torrent_file: String,
+    /// Replaces the trackers; repeat to add more, each in its own tier.
+    #[clap(long = "tracker")]
+    pub trackers: Vec<String>,
+    /// Adds a web seed URL; may be repeated.
+    #[clap(long = "web-seed")]
+    pub web_seeds: Vec<String>,
+    /// Removes the existing web seeds before adding any new ones.
+    #[clap(long)]
+    pub clear_web_seeds: bool,
+    /// Sets the comment; an empty one removes it.
+    #[clap(long)]
+    pub comment: Option<String>,
+    #[clap(long, conflicts_with = "public")]
+    pub private: bool,
+    /// Clears the private flag.
+    #[clap(long)]
+    pub public: bool,
+    /// Sets the source tag; an empty one removes it.
+    #[clap(long)]
+    pub source: Option<String>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_args() {
+        Args::command().debug_assert();
+
+        let args = Args::try_parse_from(["bt", "handshake", "a.torrent", "127.0.0.1:1"]).unwrap();
+        assert!(matches!(
+            args.cmd,
+            Commands::Handshake { ip: Some(peer), .. } if peer == "127.0.0.1:1"
+        ));
+        assert_eq!(args.identity.ip, None);
+
+        let args =
+            Args::try_parse_from(["bt", "handshake", "a.torrent", "--ip", "10.0.0.1"]).unwrap();
+        assert!(matches!(args.cmd, Commands::Handshake { ip: None, .. }));
+        assert_eq!(args.identity.ip, Some(IpAddr::from([10, 0, 0, 1])));
+    }
+}
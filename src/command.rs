@@ -1,29 +1,170 @@
 use crate::{
+    cli::EditArgs,
+    json,
+    metadata::{fetch_torrent, magnet_peers},
     peer::{ConnectedPeer, Peer, PeerMessage},
-    torrent::Torrent,
-    tracker::discover_peers,
+    tracker::{discover_peers, Announcer, Event, Trackers, TransferStats},
+    webseed::WebSeed,
 };
 use anyhow::{anyhow, Result};
-use bittorrent_starter_rust::{bitmap::BitMap, mini_serde_bencode::from_bytes};
+use bittorrent_starter_rust::{
+    bencode::BencodeValue,
+    bitmap::BitMap,
+    create::TorrentBuilder,
+    magnet::Magnet,
+    mini_serde_bencode::{from_bytes, to_bytes},
+    torrent::{MetaVersion, Torrent},
+    validate::{check_names, Problem},
+};
 use std::{
+    fmt::Display,
     fs::{self, File},
-    io::Write,
+    io::{self, Write},
+    path::{Component, Path},
     sync::Arc,
 };
 use tokio::sync::Mutex;
 
 pub fn info(torrent_file: &str) -> Result<()> {
     let torrent = parse_torrent(torrent_file)?;
+    print_info(&torrent)?;
+
+    Ok(())
+}
+
+pub fn magnet_parse(magnet_link: &str) -> Result<()> {
+    let magnet = magnet_link.parse::<Magnet>()?;
+    if let Some(tracker) = magnet.trackers.first() {
+        println!("Tracker URL: {tracker}");
+    }
+    println!("Info Hash: {}", hex::encode(magnet.info_hash));
+    if let Some(name) = &magnet.display_name {
+        println!("Name: {name}");
+    }
+    for peer in &magnet.peers {
+        println!("Peer: {peer}");
+    }
+
+    Ok(())
+}
+
+pub async fn magnet_info(magnet_link: &str) -> Result<()> {
+    let magnet = magnet_link.parse::<Magnet>()?;
+    let peers = magnet_peers(&magnet).await?;
+    let torrent = fetch_torrent(magnet, &peers.all()).await?;
+    print_info(&torrent)?;
+
+    Ok(())
+}
+
+pub async fn magnet_download(output_file: &str, magnet_link: &str) -> Result<()> {
+    let magnet = magnet_link.parse::<Magnet>()?;
+    let peers = magnet_peers(&magnet).await?;
+    let torrent = fetch_torrent(magnet, &peers.all()).await?;
+    check_valid(&torrent)?;
+    check_downloadable(&torrent)?;
+    let peers = peers.for_content(torrent.info.is_private());
+    download_announced(output_file, torrent, peers).await
+}
+
+fn print_info(torrent: &Torrent) -> Result<()> {
     let info_hash = torrent.info_hash();
 
-    println!("Tracker URL: {}", torrent.announce);
-    println!("Length: {}", torrent.info.length);
+    if let Some(announce) = &torrent.announce {
+        println!("Tracker URL: {announce}");
+    }
+    if let Some(comment) = &torrent.comment {
+        println!("Comment: {comment}");
+    }
+    if let Some(created_by) = &torrent.created_by {
+        println!("Created By: {created_by}");
+    }
+    if let Some(creation_date) = torrent.creation_date {
+        println!("Creation Date: {creation_date}");
+    }
+    if let Some(encoding) = &torrent.encoding {
+        println!("Encoding: {encoding}");
+    }
+    if torrent.info.is_private() {
+        println!("Private: yes");
+    }
+    if let Some(source) = &torrent.info.source {
+        println!("Source: {source}");
+    }
+    println!("Length: {}", torrent.length());
     println!("Info Hash: {}", hex::encode(info_hash));
+    match torrent.info.version() {
+        MetaVersion::V1 => {}
+        MetaVersion::V2 => println!("Info Hash v2: {}", hex::encode(torrent.info_hash_v2())),
+        MetaVersion::Hybrid => {
+            println!("Info Hash v2: {}", hex::encode(torrent.info_hash_v2()));
+            println!("Hybrid: yes");
+        }
+    }
     println!("Piece Length: {}", torrent.info.piece_length);
     println!("Piece Hashes:");
-    for piece in torrent.info.pieces.chunks(20) {
+    for piece in (0..torrent.piece_count()?).filter_map(|index| torrent.piece_hash(index).ok()) {
         println!("{}", hex::encode(piece));
     }
+    if !torrent.info.is_single_file() {
+        println!("Files:");
+        for file in torrent.files().iter().filter(|file| !file.padding) {
+            println!("{} ({} bytes)", file.path.display(), file.length);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn encode(json: &str, output_file: Option<&str>) -> Result<()> {
+    let value = json::from_json(&serde_json::from_str(json)?)?;
+    let bytes = to_bytes(&value)?;
+    match output_file {
+        Some(output_file) => fs::write(output_file, bytes)?,
+        None => io::stdout().write_all(&bytes)?,
+    }
+
+    Ok(())
+}
+
+pub fn create(builder: TorrentBuilder, output_file: &str) -> Result<()> {
+    let torrent = builder.build()?;
+    fs::write(output_file, to_bytes(&torrent)?)?;
+    println!("Info Hash: {}", hex::encode(torrent.info_hash()));
+
+    Ok(())
+}
+
+/// Applies the edits in `args` and writes the result. The info dictionary
+/// keeps its exact bytes unless the private flag or source changes.
+pub fn edit(args: EditArgs) -> Result<()> {
+    let original = fs::read(&args.torrent_file)?;
+    let mut torrent = from_bytes::<Torrent>(&original)?;
+    let old_info_hash = torrent.info_hash();
+
+    if !args.trackers.is_empty() {
+        torrent.set_trackers(args.trackers.into_iter().map(|url| vec![url]).collect());
+    }
+    if args.clear_web_seeds {
+        torrent.url_list.clear();
+    }
+    torrent.url_list.extend(args.web_seeds);
+    if let Some(comment) = args.comment {
+        torrent.comment = Some(comment).filter(|comment| !comment.is_empty());
+    }
+    if args.private || args.public {
+        torrent.set_private(args.private)?;
+    }
+    if let Some(source) = args.source {
+        torrent.set_source(Some(source).filter(|source| !source.is_empty()))?;
+    }
+
+    fs::write(&args.output_file, torrent.encode_onto(&original)?)?;
+    let info_hash = torrent.info_hash();
+    println!("Info Hash: {}", hex::encode(&info_hash));
+    if info_hash != old_info_hash {
+        println!("Previous Info Hash: {}", hex::encode(old_info_hash));
+    }
 
     Ok(())
 }
@@ -31,15 +172,65 @@ pub fn info(torrent_file: &str) -> Result<()> {
 pub async fn peers(torrent_file: &str) -> Result<()> {
     let torrent = parse_torrent(torrent_file)?;
     let peers = discover_peers(&torrent).await?;
-    peers.iter().for_each(|peer| println!("{}", peer.to_url()));
+    for peer in &peers {
+        println!("{}", peer.to_url());
+    }
+
+    Ok(())
+}
+
+pub async fn scrape(torrent_files: &[String]) -> Result<()> {
+    let torrents = torrent_files
+        .iter()
+        .map(|torrent_file| parse_torrent(torrent_file))
+        .collect::<Result<Vec<_>>>()?;
+    // Torrents with the same trackers go in one request.
+    let mut groups: Vec<(Vec<Vec<String>>, Vec<usize>)> = Vec::new();
+    for (i, torrent) in torrents.iter().enumerate() {
+        let tiers = torrent.tracker_tiers();
+        match groups
+            .iter_mut()
+            .find(|(group_tiers, _)| *group_tiers == tiers)
+        {
+            Some((_, members)) => members.push(i),
+            None => groups.push((tiers, vec![i])),
+        }
+    }
+
+    for (tiers, members) in groups {
+        let info_hashes: Vec<Vec<u8>> = members.iter().map(|&i| torrents[i].info_hash()).collect();
+        let info_hashes: Vec<&[u8]> = info_hashes.iter().map(Vec::as_slice).collect();
+        let stats = Trackers::new(tiers).scrape(&info_hashes).await;
+        for (j, &i) in members.iter().enumerate() {
+            println!("{} ({}):", torrent_files[i], hex::encode(info_hashes[j]));
+            match &stats {
+                Ok(stats) => match stats[j] {
+                    Some(stats) => {
+                        println!("  Complete: {}", stats.complete);
+                        println!("  Incomplete: {}", stats.incomplete);
+                        println!("  Downloaded: {}", stats.downloaded);
+                    }
+                    None => println!("  Not known to the tracker"),
+                },
+                Err(e) => println!("  Scrape failed: {e}"),
+            }
+        }
+    }
 
     Ok(())
 }
 
-pub async fn handshake(torrent_file: &str) -> Result<()> {
+/// Shakes hands with `peer` if given, otherwise with the first peer the
+/// tracker returns.
+pub async fn handshake(torrent_file: &str, peer: Option<&str>) -> Result<()> {
     let torrent = parse_torrent(torrent_file)?;
-    let peers = discover_peers(&torrent).await?;
-    let peer = peers.first().ok_or(anyhow!("No peers found"))?;
+    let peer = match peer {
+        Some(peer) => peer.parse::<Peer>()?,
+        None => *discover_peers(&torrent)
+            .await?
+            .first()
+            .ok_or(anyhow!("No peers found"))?,
+    };
     let torrent = Arc::new(torrent);
     let peer = peer.connect(torrent).await?;
     println!("Peer ID: {}", peer.peer_id);
@@ -53,6 +244,8 @@ pub async fn download_and_write_piece(
     piece_index: u32,
 ) -> Result<()> {
     let torrent = parse_torrent(torrent_file)?;
+    check_downloadable(&torrent)?;
+    torrent.piece_range(piece_index)?;
     let peers = discover_peers(&torrent).await?;
     let torrent = Arc::new(torrent);
     let mut connected_peers = connect_to_peers(peers, torrent.clone()).await?;
@@ -70,40 +263,113 @@ pub async fn download_and_write_piece(
 
 pub async fn download(output_file: &str, torrent_file: &str) -> Result<()> {
     let torrent = parse_torrent(torrent_file)?;
-    let peers = discover_peers(&torrent).await?;
+    check_downloadable(&torrent)?;
+    download_announced(output_file, torrent, Vec::new()).await
+}
+
+/// Downloads from `peers`, the peers the trackers return and any web seeds,
+/// keeping the trackers informed of progress until the download ends.
+async fn download_announced(
+    output_file: &str,
+    torrent: Torrent,
+    mut peers: Vec<Peer>,
+) -> Result<()> {
+    let stats = Arc::new(TransferStats::new(torrent.length()));
+    let mut announcer = Announcer::new(
+        torrent.info_hash(),
+        torrent.tracker_tiers(),
+        Arc::clone(&stats),
+    );
+    let has_web_seeds = !torrent.url_list.is_empty() || !torrent.http_seeds.is_empty();
+    match announcer.announce(Some(Event::Started)).await {
+        Ok(found) => {
+            for peer in found {
+                if !peers.contains(&peer) {
+                    peers.push(peer);
+                }
+            }
+        }
+        Err(e) if has_web_seeds || !peers.is_empty() => println!("No peers from trackers: {e}"),
+        Err(e) => return Err(e),
+    }
+
+    let announcer = announcer.spawn();
+    let result = download_torrent(output_file, torrent, peers, &stats).await;
+    announcer.stop(result.is_ok()).await;
+    result
+}
+
+async fn download_torrent(
+    output_file: &str,
+    torrent: Torrent,
+    peers: Vec<Peer>,
+    stats: &Arc<TransferStats>,
+) -> Result<()> {
     let torrent = Arc::new(torrent);
 
-    let piece_count = torrent.info.pieces.len() / 20;
-    let piece_len = torrent.info.piece_length as usize;
-    let pieces: Arc<Mutex<Vec<u8>>> = Arc::new(Mutex::new(vec![0; torrent.info.length as usize]));
+    let piece_count = torrent.piece_count()?;
+    let pieces: Arc<Mutex<Vec<u8>>> =
+        Arc::new(Mutex::new(vec![0; usize::try_from(torrent.length())?]));
 
-    let connected_peers = connect_to_peers(peers, torrent).await?;
-    let piece_idxs = Arc::new(Mutex::new((0..piece_count).collect::<Vec<usize>>()));
+    let web_seeds = WebSeed::all(&torrent);
+    let connected_peers = match connect_to_peers(peers, torrent.clone()).await {
+        Ok(peers) => peers,
+        Err(e) if !web_seeds.is_empty() => {
+            println!("{e}, downloading from web seeds only");
+            Vec::new()
+        }
+        Err(e) => return Err(e),
+    };
+    let sources = connected_peers
+        .into_iter()
+        .map(PieceSource::Peer)
+        .chain(web_seeds.into_iter().map(PieceSource::WebSeed));
+
+    let piece_idxs = Arc::new(Mutex::new((0..piece_count).collect::<Vec<u32>>()));
     let mut tasks = vec![];
 
-    for (peer_idx, mut peer) in connected_peers.into_iter().enumerate() {
+    for (source_idx, mut source) in sources.enumerate() {
         let piece_idxs = Arc::clone(&piece_idxs);
         let pieces = Arc::clone(&pieces);
+        let torrent = Arc::clone(&torrent);
+        let stats = Arc::clone(stats);
         let task = tokio::spawn(async move {
+            println!("Source {source_idx} is {source}");
+            let mut failures = 0;
             loop {
                 let mut lock = piece_idxs.lock().await;
-                println!("Peer {peer_idx} has the lock");
+                println!("Source {source_idx} has the lock");
                 if let Some(piece_index) = lock.pop() {
                     drop(lock);
                     println!(
-                        "Peer {peer_idx} dropped the lock and is downloading piece {piece_index}"
+                        "Source {source_idx} dropped the lock and is downloading piece {piece_index}"
                     );
-                    if let Ok(piece) = peer.download_piece(piece_index as u32).await {
-                        println!("Peer {peer_idx} downloaded piece {piece_index}");
-                        let start = piece_index * piece_len;
-                        let end = start + piece.len();
-                        let mut pieces = pieces.lock().await;
-                        pieces.splice(start..end, piece);
-                    } else {
-                        {
-                            let mut lock = piece_idxs.lock().await;
-                            println!("Peer {peer_idx} failed to download piece {piece_index}");
-                            lock.push(piece_index);
+                    match source.download_piece(piece_index).await {
+                        Ok(piece) => {
+                            println!("Source {source_idx} downloaded piece {piece_index}");
+                            failures = 0;
+                            stats.add_downloaded(piece.len() as u64);
+                            let range = torrent
+                                .piece_range(piece_index)
+                                .expect("piece indices come from the piece count");
+                            let start = usize::try_from(range.start).expect("content fits memory");
+                            let end = usize::try_from(range.end).expect("content fits memory");
+                            let mut pieces = pieces.lock().await;
+                            pieces[start..end].copy_from_slice(&piece);
+                        }
+                        Err(e) => {
+                            {
+                                let mut lock = piece_idxs.lock().await;
+                                println!(
+                                    "Source {source_idx} failed to download piece {piece_index}: {e}"
+                                );
+                                lock.push(piece_index);
+                            }
+                            failures += 1;
+                            if failures == MAX_CONSECUTIVE_FAILURES {
+                                println!("Giving up on source {source_idx}");
+                                break;
+                            }
                         }
                     }
                 } else {
@@ -118,18 +384,125 @@ pub async fn download(output_file: &str, torrent_file: &str) -> Result<()> {
         task.await?;
     }
 
+    let missing = piece_idxs.lock().await.len();
+    if missing > 0 {
+        return Err(anyhow!("{missing} pieces could not be downloaded"));
+    }
+
     let pieces = pieces.lock().await;
-    write_piece(&pieces, output_file)?;
+    write_content(&torrent, &pieces, output_file)?;
 
     Ok(())
 }
 
+/// Where pieces of a download come from.
+enum PieceSource {
+    Peer(ConnectedPeer),
+    WebSeed(WebSeed),
+}
+
+impl PieceSource {
+    async fn download_piece(&mut self, piece_index: u32) -> Result<Vec<u8>> {
+        match self {
+            PieceSource::Peer(peer) => peer.download_piece(piece_index).await,
+            PieceSource::WebSeed(web_seed) => web_seed.download_piece(piece_index).await,
+        }
+    }
+}
+
+impl Display for PieceSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PieceSource::Peer(peer) => write!(f, "peer {}", peer.peer),
+            PieceSource::WebSeed(web_seed) => write!(f, "web seed {web_seed}"),
+        }
+    }
+}
+
+// A source failing this many pieces in a row is dropped from the download.
+const MAX_CONSECUTIVE_FAILURES: u32 = 5;
+
 fn parse_torrent(torrent_file: &str) -> Result<Torrent> {
     let content = fs::read(torrent_file)?;
     let torrent = from_bytes::<Torrent>(&content)?;
+    check_valid(&torrent)?;
     Ok(torrent)
 }
 
+/// Rejects torrents that would make downloading panic or write outside the
+/// output directory.
+fn check_valid(torrent: &Torrent) -> Result<()> {
+    match torrent.validate().into_iter().find(Problem::is_fatal) {
+        Some(problem) => Err(anyhow!("Invalid torrent: {problem}")),
+        None => Ok(()),
+    }
+}
+
+/// Reports every problem with a torrent file, including names that aren't
+/// UTF-8 and so keep it from parsing. Fails if any of them is fatal.
+pub fn verify_meta(torrent_file: &str) -> Result<()> {
+    let content = fs::read(torrent_file)?;
+    let value = from_bytes::<BencodeValue>(&content)?;
+    let mut problems = value.get("info").map(check_names).unwrap_or_default();
+    match from_bytes::<Torrent>(&content) {
+        Ok(torrent) => problems.extend(torrent.validate()),
+        Err(e) if problems.is_empty() => return Err(e.into()),
+        Err(_) => {}
+    }
+
+    for problem in &problems {
+        let severity = if problem.is_fatal() {
+            "error"
+        } else {
+            "warning"
+        };
+        println!("{severity}: {problem}");
+    }
+    let fatal = problems.iter().filter(|problem| problem.is_fatal()).count();
+    if fatal > 0 {
+        return Err(anyhow!("{fatal} fatal problem(s) found"));
+    }
+    println!("OK");
+    Ok(())
+}
+
+/// Pieces are checked against the v1 SHA-1 hashes, which v2-only torrents
+/// lack.
+fn check_downloadable(torrent: &Torrent) -> Result<()> {
+    if torrent.info.version() == MetaVersion::V2 {
+        return Err(anyhow!("Downloading v2-only torrents is not supported"));
+    }
+    Ok(())
+}
+
+/// Writes a single-file torrent to `output`, and the files of a multi-file
+/// torrent to the directory tree under `output/<name>`.
+fn write_content(torrent: &Torrent, content: &[u8], output: &str) -> Result<()> {
+    if torrent.info.is_single_file() {
+        return write_piece(content, output);
+    }
+
+    for file in torrent.files().iter().filter(|file| !file.padding) {
+        // Paths come from the torrent, so they must not escape `output`.
+        if !file
+            .path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(anyhow!("Unsafe file path: {}", file.path.display()));
+        }
+        let path = Path::new(output).join(&file.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let start = usize::try_from(file.offset)?;
+        let end = start + usize::try_from(file.length)?;
+        fs::write(path, &content[start..end])?;
+    }
+
+    Ok(())
+}
+
 fn write_piece(piece: &[u8], output_file: &str) -> Result<()> {
     let mut tmp_file = File::create(output_file)?;
     tmp_file.write_all(piece)?;
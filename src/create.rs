@@ -0,0 +1,533 @@
+//! Building metainfo files for local content.
+
+use crate::{
+    mini_serde_bencode::{self, WithRaw},
+    torrent::{FileEntry, Info, Layout, Torrent},
+};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MIN_PIECE_LENGTH: u32 = 16 * 1024;
+const MAX_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+// Aim for roughly this many pieces when picking the piece length.
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CreateError {
+    #[error("{}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("{}: file name is not valid UTF-8", .0.display())]
+    NonUtf8Path(PathBuf),
+    #[error("{}: no files to share", .0.display())]
+    NoFiles(PathBuf),
+    #[error("piece length {0} is not a power of two of at least 16 KiB")]
+    InvalidPieceLength(u32),
+    #[error(transparent)]
+    Bencode(#[from] mini_serde_bencode::Error),
+}
+
+pub type Result<T> = std::result::Result<T, CreateError>;
+
+/// Creates a [`Torrent`] for a file or directory.
+///
+/// ```no_run
+/// # use bittorrent_starter_rust::create::TorrentBuilder;
+/// let torrent = TorrentBuilder::new("content")
+///     .tracker("http://tracker.example/announce")
+///     .private(true)
+///     .build()?;
+/// # Ok::<(), bittorrent_starter_rust::create::CreateError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    trackers: Vec<String>,
+    web_seeds: Vec<String>,
+    comment: Option<String>,
+    private: bool,
+    piece_length: Option<u32>,
+    threads: Option<usize>,
+}
+
+impl TorrentBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            trackers: Vec::new(),
+            web_seeds: Vec::new(),
+            comment: None,
+            private: false,
+            piece_length: None,
+            threads: None,
+        }
+    }
+
+    /// Adds a tracker. The first one becomes `announce`; with more than one,
+    /// each gets its own `announce-list` tier. Without any, the torrent is
+    /// trackerless and peers come from elsewhere, such as web seeds.
+    pub fn tracker(mut self, url: impl Into<String>) -> Self {
+        self.trackers.push(url.into());
+        self
+    }
+
+    pub fn web_seed(mut self, url: impl Into<String>) -> Self {
+        self.web_seeds.push(url.into());
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Overrides the piece length, which is otherwise picked from the total
+    /// size.
+    pub fn piece_length(mut self, piece_length: u32) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// How many threads hash pieces; defaults to the available parallelism.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    pub fn build(self) -> Result<Torrent> {
+        let name = file_name(&self.path)?;
+        let files = collect_files(&self.path)?;
+        if files.is_empty() {
+            return Err(CreateError::NoFiles(self.path));
+        }
+
+        let length = files.iter().map(|file| file.length).sum();
+        let piece_length = match self.piece_length {
+            Some(piece_length)
+                if piece_length.is_power_of_two() && piece_length >= MIN_PIECE_LENGTH =>
+            {
+                piece_length
+            }
+            Some(piece_length) => return Err(CreateError::InvalidPieceLength(piece_length)),
+            None => choose_piece_length(length),
+        };
+        let threads = self
+            .threads
+            .or_else(|| thread::available_parallelism().ok().map(usize::from))
+            .unwrap_or(1);
+        let pieces = hash_pieces(&files, length, piece_length, threads)?;
+
+        let layout = if fs::metadata(&self.path)
+            .map_err(|source| io_error(&self.path, source))?
+            .is_dir()
+        {
+            Layout::MultiFile {
+                files: files
+                    .into_iter()
+                    .map(|file| FileEntry {
+                        length: file.length,
+                        path: file.components,
+                        attr: None,
+                    })
+                    .collect(),
+            }
+        } else {
+            Layout::SingleFile { length }
+        };
+        let info = Info {
+            name,
+            piece_length,
+            pieces,
+            private: self.private.then_some(true),
+            source: None,
+            meta_version: None,
+            file_tree: None,
+            layout: Some(layout),
+        };
+
+        let creation_date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .and_then(|since| i64::try_from(since.as_secs()).ok());
+        let mut torrent = Torrent {
+            announce: None,
+            announce_list: Vec::new(),
+            comment: self.comment,
+            created_by: Some(
+                concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")).to_string(),
+            ),
+            creation_date,
+            encoding: None,
+            url_list: self.web_seeds,
+            http_seeds: Vec::new(),
+            piece_layers: BTreeMap::new(),
+            files: OnceLock::new(),
+            info: WithRaw::new(info)?,
+        };
+        torrent.set_trackers(self.trackers.into_iter().map(|url| vec![url]).collect());
+        Ok(torrent)
+    }
+}
+
+/// The smallest power of two that splits `length` into about
+/// 1500 pieces, kept between 16 KiB and 16 MiB.
+pub fn choose_piece_length(length: u64) -> u32 {
+    let piece_length = length.div_ceil(TARGET_PIECE_COUNT).next_power_of_two();
+    u32::try_from(piece_length)
+        .unwrap_or(MAX_PIECE_LENGTH)
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+struct SourceFile {
+    path: PathBuf,
+    // The path below the torrent's root, empty for a single-file torrent.
+    components: Vec<String>,
+    length: u64,
+}
+
+fn io_error(path: &Path, source: io::Error) -> CreateError {
+    CreateError::Io {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+fn file_name(path: &Path) -> Result<String> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|source| io_error(path, source))?;
+    canonical
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| CreateError::NonUtf8Path(path.to_path_buf()))
+}
+
+/// Lists the regular files under `root` in a stable order, skipping empty
+/// directories. Symbolic links below `root` are skipped, so a link back to an
+/// ancestor can't send the walk round in circles.
+fn collect_files(root: &Path) -> Result<Vec<SourceFile>> {
+    let metadata = fs::metadata(root).map_err(|source| io_error(root, source))?;
+    if metadata.is_file() {
+        return Ok(vec![SourceFile {
+            path: root.to_path_buf(),
+            components: Vec::new(),
+            length: metadata.len(),
+        }]);
+    }
+
+    let mut files = Vec::new();
+    let mut dirs = vec![(root.to_path_buf(), Vec::new())];
+    while let Some((dir, components)) = dirs.pop() {
+        let entries = fs::read_dir(&dir).map_err(|source| io_error(&dir, source))?;
+        for entry in entries {
+            let entry = entry.map_err(|source| io_error(&dir, source))?;
+            let path = entry.path();
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|_| CreateError::NonUtf8Path(path.clone()))?;
+            let mut components = components.clone();
+            components.push(name);
+            let metadata = fs::symlink_metadata(&path).map_err(|source| io_error(&path, source))?;
+            if metadata.is_dir() {
+                dirs.push((path, components));
+            } else if metadata.is_file() {
+                files.push(SourceFile {
+                    path,
+                    components,
+                    length: metadata.len(),
+                });
+            }
+        }
+    }
+    files.sort_by(|a, b| a.components.cmp(&b.components));
+    Ok(files)
+}
+
+/// Hashes the concatenated content of `files`, spreading pieces over
+/// `threads` threads.
+fn hash_pieces(
+    files: &[SourceFile],
+    length: u64,
+    piece_length: u32,
+    threads: usize,
+) -> Result<Vec<u8>> {
+    let piece_count = usize::try_from(length.div_ceil(u64::from(piece_length)))
+        .expect("piece count fits in memory");
+    let threads = threads.clamp(1, piece_count.max(1));
+    let mut pieces = vec![0; piece_count * 20];
+
+    // Each thread hashes a contiguous run of pieces, so it reads its part
+    // of the content front to back.
+    let pieces_per_thread = piece_count.div_ceil(threads).max(1);
+    thread::scope(|scope| {
+        let handles: Vec<_> = pieces
+            .chunks_mut(pieces_per_thread * 20)
+            .enumerate()
+            .map(|(i, hashes)| {
+                scope.spawn(move || {
+                    let first_piece = i * pieces_per_thread;
+                    let mut reader = ContentReader::new(files);
+                    let mut buf = Vec::with_capacity(piece_length as usize);
+                    for (j, hash) in hashes.chunks_mut(20).enumerate() {
+                        let offset = (first_piece + j) as u64 * u64::from(piece_length);
+                        let len = u64::from(piece_length).min(length - offset);
+                        reader.read_range(offset, len, &mut buf)?;
+                        hash.copy_from_slice(&Sha1::digest(&buf));
+                    }
+                    Ok::<_, CreateError>(())
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().expect("hashing thread panicked"))
+    })?;
+
+    Ok(pieces)
+}
+
+/// Reads the concatenated content of a list of files, keeping the current
+/// file open so that reading front to back opens each file once.
+struct ContentReader<'a> {
+    files: &'a [SourceFile],
+    // Where each file starts in the concatenated content.
+    starts: Vec<u64>,
+    // The open file's index in `files`, its handle and the read position.
+    open: Option<(usize, File, u64)>,
+}
+
+impl<'a> ContentReader<'a> {
+    fn new(files: &'a [SourceFile]) -> Self {
+        let starts = files
+            .iter()
+            .scan(0, |start, file| {
+                let file_start = *start;
+                *start += file.length;
+                Some(file_start)
+            })
+            .collect();
+        Self {
+            files,
+            starts,
+            open: None,
+        }
+    }
+
+    /// Reads `len` bytes starting at `offset` of the concatenated content.
+    fn read_range(&mut self, mut offset: u64, len: u64, buf: &mut Vec<u8>) -> Result<()> {
+        buf.clear();
+        let end = offset + len;
+        // The last file starting at or before `offset`, which skips any empty
+        // files sharing its start.
+        let mut index = self
+            .starts
+            .partition_point(|&start| start <= offset)
+            .saturating_sub(1);
+        while offset < end {
+            let file_start = self.starts[index];
+            let file_end = file_start + self.files[index].length;
+            if offset < file_end {
+                let want = file_end.min(end) - offset;
+                self.read_file(index, offset - file_start, want, buf)
+                    .map_err(|source| io_error(&self.files[index].path, source))?;
+                offset += want;
+            }
+            index += 1;
+        }
+        Ok(())
+    }
+
+    /// Appends `want` bytes from `position` in file `index` to `buf`.
+    fn read_file(
+        &mut self,
+        index: usize,
+        position: u64,
+        want: u64,
+        buf: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        match &mut self.open {
+            Some((open, _, at)) if *open == index && *at == position => {}
+            Some((open, file, at)) if *open == index => {
+                file.seek(SeekFrom::Start(position))?;
+                *at = position;
+            }
+            _ => {
+                let mut file = File::open(&self.files[index].path)?;
+                file.seek(SeekFrom::Start(position))?;
+                self.open = Some((index, file, position));
+            }
+        }
+        let (_, file, at) = self.open.as_mut().expect("the file was just opened");
+        let got = file.take(want).read_to_end(buf)? as u64;
+        *at += got;
+        if got < want {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file shrank while hashing",
+            ));
+        }
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mini_serde_bencode::{from_bytes, to_bytes};
+
+    #[test]
+    fn test_choose_piece_length() {
+        assert_eq!(choose_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(choose_piece_length(1_000_000), MIN_PIECE_LENGTH);
+        assert_eq!(choose_piece_length(700 * 1024 * 1024), 512 * 1024);
+        assert_eq!(choose_piece_length(u64::MAX), MAX_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn test_create_single_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spam.txt");
+        let content: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+        fs::write(&path, &content).unwrap();
+
+        let torrent = TorrentBuilder::new(&path)
+            .tracker("http://a/")
+            .comment("hello")
+            .build()
+            .unwrap();
+        assert_eq!(torrent.info.name, "spam.txt");
+        assert_eq!(torrent.info.length(), 40_000);
+        assert_eq!(torrent.info.piece_length, MIN_PIECE_LENGTH);
+        let expected: Vec<u8> = content
+            .chunks(MIN_PIECE_LENGTH as usize)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        assert_eq!(torrent.info.pieces, expected);
+
+        let bytes = to_bytes(&torrent).unwrap();
+        let parsed = from_bytes::<Torrent>(&bytes).unwrap();
+        assert_eq!(parsed.info_hash(), torrent.info_hash());
+        assert_eq!(parsed.comment.as_deref(), Some("hello"));
+
+        let torrent = TorrentBuilder::new(&path).build().unwrap();
+        assert_eq!(torrent.announce, None);
+        assert!(torrent.tracker_tiers().is_empty());
+    }
+
+    #[test]
+    fn test_create_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("content");
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::create_dir_all(root.join("empty")).unwrap();
+        fs::write(root.join("b/c.txt"), vec![1; 20_000]).unwrap();
+        fs::write(root.join("a.txt"), vec![2; 30_000]).unwrap();
+
+        let torrent = TorrentBuilder::new(&root)
+            .tracker("http://a/")
+            .tracker("http://b/")
+            .web_seed("http://w/")
+            .private(true)
+            .threads(4)
+            .build()
+            .unwrap();
+        assert_eq!(torrent.announce_list, [["http://a/"], ["http://b/"]]);
+        assert_eq!(torrent.url_list, ["http://w/"]);
+        assert!(torrent.info.is_private());
+        let files = torrent.info.files();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].path, Path::new("content/a.txt"));
+        assert_eq!(files[1].path, Path::new("content/b/c.txt"));
+
+        // The second piece straddles both files.
+        let mut content = vec![2; 30_000];
+        content.extend(vec![1; 20_000]);
+        let expected: Vec<u8> = content
+            .chunks(MIN_PIECE_LENGTH as usize)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        assert_eq!(torrent.info.pieces, expected);
+    }
+
+    #[test]
+    fn test_create_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            TorrentBuilder::new(dir.path()).tracker("http://a/").build(),
+            Err(CreateError::NoFiles(_))
+        ));
+        fs::write(dir.path().join("a"), b"a").unwrap();
+        assert!(matches!(
+            TorrentBuilder::new(dir.path())
+                .tracker("http://a/")
+                .piece_length(1000)
+                .build(),
+            Err(CreateError::InvalidPieceLength(1000))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_skips_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("content");
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a.txt"), b"spam").unwrap();
+        std::os::unix::fs::symlink(&root, root.join("b/loop")).unwrap();
+        std::os::unix::fs::symlink(root.join("a.txt"), root.join("link.txt")).unwrap();
+
+        let torrent = TorrentBuilder::new(&root)
+            .tracker("http://a/")
+            .build()
+            .unwrap();
+        let files = torrent.info.files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, Path::new("content/a.txt"));
+    }
+
+    #[test]
+    fn test_content_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: Vec<_> = [&b"abc"[..], b"", b"defg"]
+            .iter()
+            .enumerate()
+            .map(|(i, content)| {
+                let path = dir.path().join(i.to_string());
+                fs::write(&path, content).unwrap();
+                SourceFile {
+                    path,
+                    components: vec![i.to_string()],
+                    length: content.len() as u64,
+                }
+            })
+            .collect();
+
+        let mut reader = ContentReader::new(&files);
+        let mut buf = Vec::new();
+        for (offset, len, expected) in [
+            (0, 2, &b"ab"[..]),
+            (2, 2, b"cd"),
+            (4, 3, b"efg"),
+            (1, 5, b"bcdef"),
+        ] {
+            reader.read_range(offset, len, &mut buf).unwrap();
+            assert_eq!(buf, expected);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Who this client says it is to trackers and peers. The identity is picked
+//! once per session so that every announce and handshake agrees.
+
+use anyhow::{anyhow, Result};
+use std::{net::IpAddr, sync::OnceLock};
+
+use crate::{cli::IdentityArgs, random::random_u32};
+
+// Azureus style: a client code and version between dashes.
+const PEER_ID_PREFIX: &[u8; 8] = b"-XX0001-";
+
+static SESSION: OnceLock<Identity> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub peer_id: [u8; 20],
+    /// The port we tell trackers peers can reach us on.
+    pub port: u16,
+    /// Lets trackers recognize us when our IP address changes.
+    pub key: u32,
+    /// How many peers to ask trackers for; `None` leaves it to them.
+    pub numwant: Option<u32>,
+    /// Our address, for trackers that can't tell it from the connection.
+    pub ip: Option<IpAddr>,
+}
+
+impl Identity {
+    /// A fresh identity with a random peer id and key.
+    pub fn generate() -> Self {
+        Self {
+            peer_id: random_peer_id(),
+            port: 6881,
+            key: random_u32(),
+            numwant: None,
+            ip: None,
+        }
+    }
+
+    /// A fresh identity with whatever `args` sets overridden.
+    pub fn from_args(args: IdentityArgs) -> Self {
+        let generated = Self::generate();
+        Self {
+            peer_id: args.peer_id.unwrap_or(generated.peer_id),
+            port: args.port,
+            key: args.key.unwrap_or(generated.key),
+            numwant: args.numwant,
+            ip: args.ip,
+        }
+    }
+
+    /// The session's identity, generated on first use unless one was set.
+    pub fn session() -> &'static Identity {
+        SESSION.get_or_init(Identity::generate)
+    }
+
+    /// Makes this the session's identity. Fails once the session has one.
+    pub fn set_session(self) -> Result<()> {
+        SESSION
+            .set(self)
+            .map_err(|_| anyhow!("The session identity is already set"))
+    }
+}
+
+/// `-XX0001-` followed by twelve random bytes.
+fn random_peer_id() -> [u8; 20] {
+    let mut peer_id = [0; 20];
+    peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+    for chunk in peer_id[8..].chunks_exact_mut(4) {
+        chunk.copy_from_slice(&random_u32().to_be_bytes());
+    }
+    peer_id
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let (a, b) = (Identity::generate(), Identity::generate());
+        assert!(a.peer_id.starts_with(b"-XX0001-"));
+        assert_ne!(a.peer_id, b.peer_id);
+        assert_eq!(a.port, 6881);
+
+        let args = IdentityArgs {
+            peer_id: Some(*b"-AB1234-aaaaaaaaaaaa"),
+            port: 51413,
+            key: None,
+            numwant: Some(50),
+            ip: None,
+        };
+        let identity = Identity::from_args(args);
+        assert_eq!(&identity.peer_id, b"-AB1234-aaaaaaaaaaaa");
+        assert_eq!((identity.port, identity.numwant), (51413, Some(50)));
+    }
+}
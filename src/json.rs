@@ -0,0 +1,217 @@
+//! Converting bencode documents to and from JSON. Byte strings that are
+//! valid UTF-8 become JSON strings and dictionaries become objects.
+//! Everything JSON cannot hold directly is written as an object with a single
+//! `$`-prefixed key:
+//!
+//! | Bencode                                       | JSON                          |
+//! |-----------------------------------------------|-------------------------------|
+//! | a byte string that is not UTF-8               | `{"$hex": "<hex>"}`           |
+//! | an integer outside the `i64`/`u64` range      | `{"$int": "<digits>"}`        |
+//! | a dictionary with a key that is not UTF-8, or | `{"$dict": [[key, value]...]}`|
+//! | with one key that starts with `$`             |                               |
+//!
+//! so any document survives the round trip unchanged.
+
+use bittorrent_starter_rust::{
+    bencode::{BencodeValue, Dict},
+    mini_serde_bencode::{self, BigInteger, Error, Path, PathSegment},
+};
+use serde_bytes::ByteBuf;
+use serde_json::{Map, Value};
+
+const HEX_TAG: &str = "$hex";
+const INT_TAG: &str = "$int";
+const DICT_TAG: &str = "$dict";
+
+/// Converts a document to JSON, tagging what JSON cannot hold as described
+/// in the [module docs](self).
+pub fn to_json(value: &BencodeValue) -> Value {
+    match value {
+        BencodeValue::String(s) => bytes_to_json(s),
+        BencodeValue::Integer(i) => Value::from(*i),
+        BencodeValue::BigInteger(i) => i
+            .as_str()
+            .parse::<u64>()
+            .map_or_else(|_| tagged(INT_TAG, i.as_str().into()), Value::from),
+        BencodeValue::List(l) => l.iter().map(to_json).collect(),
+        BencodeValue::Dictionary(d) => {
+            let keys = d
+                .keys()
+                .map(|key| std::str::from_utf8(key).ok())
+                .collect::<Option<Vec<_>>>();
+            match keys {
+                Some(keys) if !(keys.len() == 1 && keys[0].starts_with('$')) => keys
+                    .into_iter()
+                    .zip(d.values())
+                    .map(|(key, value)| (key.to_string(), to_json(value)))
+                    .collect::<Map<_, _>>()
+                    .into(),
+                _ => {
+                    let entries = d
+                        .iter()
+                        .map(|(key, value)| Value::from(vec![bytes_to_json(key), to_json(value)]))
+                        .collect();
+                    tagged(DICT_TAG, entries)
+                }
+            }
+        }
+    }
+}
+
+/// Converts JSON produced by [`to_json`] back into a document. Booleans
+/// become `0` and `1`; `null` and floats have no bencode form and fail.
+pub fn from_json(value: &Value) -> mini_serde_bencode::Result<BencodeValue> {
+    from_json_at(value, &mut Vec::new())
+}
+
+fn bytes_to_json(bytes: &[u8]) -> Value {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.into(),
+        Err(_) => tagged(HEX_TAG, hex::encode(bytes).into()),
+    }
+}
+
+fn tagged(tag: &str, value: Value) -> Value {
+    Value::Object(Map::from_iter([(tag.to_string(), value)]))
+}
+
+fn from_json_at(
+    value: &Value,
+    path: &mut Vec<PathSegment>,
+) -> mini_serde_bencode::Result<BencodeValue> {
+    match value {
+        Value::Null => Err(json_error("null has no bencode form", path)),
+        Value::Bool(b) => Ok(BencodeValue::Integer(i64::from(*b))),
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => Ok(i.into()),
+            (_, Some(i)) => Ok(i.into()),
+            _ => Err(json_error("floats have no bencode form", path)),
+        },
+        Value::String(s) => Ok(s.as_str().into()),
+        Value::Array(a) => a
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                nested(path, PathSegment::Index(i), |path| {
+                    from_json_at(value, path)
+                })
+            })
+            .collect::<mini_serde_bencode::Result<Vec<_>>>()
+            .map(BencodeValue::List),
+        Value::Object(o) => match o.iter().next() {
+            Some((tag, value)) if o.len() == 1 && tag.starts_with('$') => {
+                nested(path, PathSegment::Key(tag.clone()), |path| {
+                    from_tagged(tag, value, path)
+                })
+            }
+            _ => o
+                .iter()
+                .map(|(key, value)| {
+                    let value = nested(path, PathSegment::Key(key.clone()), |path| {
+                        from_json_at(value, path)
+                    })?;
+                    Ok((ByteBuf::from(key.as_bytes()), value))
+                })
+                .collect::<mini_serde_bencode::Result<Dict>>()
+                .map(BencodeValue::Dictionary),
+        },
+    }
+}
+
+fn from_tagged(
+    tag: &str,
+    value: &Value,
+    path: &mut Vec<PathSegment>,
+) -> mini_serde_bencode::Result<BencodeValue> {
+    match (tag, value) {
+        (HEX_TAG, Value::String(s)) => hex::decode(s)
+            .map(BencodeValue::String)
+            .map_err(|_| json_error("invalid hex string", path)),
+        (INT_TAG, Value::String(s)) => match s.parse::<i64>() {
+            Ok(i) => Ok(i.into()),
+            Err(_) => BigInteger::new(s.as_str())
+                .map(BencodeValue::BigInteger)
+                .map_err(|_| json_error("invalid integer", path)),
+        },
+        (DICT_TAG, Value::Array(entries)) => {
+            let mut dict = Dict::new();
+            for (i, entry) in entries.iter().enumerate() {
+                let (key, value) = nested(path, PathSegment::Index(i), |path| {
+                    let Some([key, value]) = entry.as_array().map(Vec::as_slice) else {
+                        return Err(json_error("expected a [key, value] pair", path));
+                    };
+                    let BencodeValue::String(key) = from_json_at(key, path)? else {
+                        return Err(json_error("expected a string key", path));
+                    };
+                    Ok((key, from_json_at(value, path)?))
+                })?;
+                dict.insert(ByteBuf::from(key), value);
+            }
+            Ok(BencodeValue::Dictionary(dict))
+        }
+        (HEX_TAG | INT_TAG, _) => Err(json_error("expected a string", path)),
+        (DICT_TAG, _) => Err(json_error("expected an array of entries", path)),
+        _ => Err(json_error(&format!("unknown tag `{tag}`"), path)),
+    }
+}
+
+fn nested<T>(
+    path: &mut Vec<PathSegment>,
+    segment: PathSegment,
+    f: impl FnOnce(&mut Vec<PathSegment>) -> T,
+) -> T {
+    path.push(segment);
+    let t = f(path);
+    path.pop();
+    t
+}
+
+fn json_error(message: &str, path: &[PathSegment]) -> Error {
+    Error::Message(format!("{message} at {}", Path(path.to_vec())))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mini_serde_bencode::to_string;
+
+    #[test]
+    fn test_json() {
+        let value = BencodeValue::dict()
+            .entry("name", "spam")
+            .entry("pieces", vec![0xffu8, 0x00])
+            .entry("length", BigInteger::new("18446744073709551616").unwrap())
+            .entry("files", vec![BencodeValue::dict().entry("$ref", 1).build()])
+            .entry(
+                "layers",
+                BencodeValue::dict().entry(vec![0xfeu8], "root").build(),
+            )
+            .build();
+        let json = to_json(&value);
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "name": "spam",
+                "pieces": {"$hex": "ff00"},
+                "length": {"$int": "18446744073709551616"},
+                "files": [{"$dict": [["$ref", 1]]}],
+                "layers": {"$dict": [[{"$hex": "fe"}, "root"]]},
+            })
+        );
+        assert_eq!(from_json(&json).unwrap(), value);
+    }
+
+    #[test]
+    fn test_json_errors() {
+        let err = from_json(&serde_json::json!({"a": [1, null]})).unwrap_err();
+        assert_eq!(err.to_string(), "null has no bencode form at a[1]");
+
+        let err = from_json(&serde_json::json!({"$b64": ""})).unwrap_err();
+        assert_eq!(err.to_string(), "unknown tag `$b64` at $b64");
+
+        let value = from_json(&serde_json::json!([true, u64::MAX])).unwrap();
+        assert_eq!(to_string(&value).unwrap(), "li1ei18446744073709551615ee");
+    }
+}
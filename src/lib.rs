@@ -1,2 +1,8 @@
+pub mod bencode;
 pub mod bitmap;
+pub mod create;
+pub mod magnet;
 pub mod mini_serde_bencode;
+pub mod sha256;
+pub mod torrent;
+pub mod validate;
@@ -0,0 +1,237 @@
+//! Magnet links (BEP 9).
+
+use crate::{
+    mini_serde_bencode::WithRaw,
+    torrent::{Info, Torrent},
+};
+use std::{collections::BTreeMap, fmt, str::FromStr, sync::OnceLock};
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum MagnetError {
+    #[error("not a magnet link")]
+    NotMagnet,
+    #[error("no `xt=urn:btih:` info hash")]
+    MissingInfoHash,
+    #[error("invalid info hash {0:?}")]
+    InvalidInfoHash(String),
+    #[error("invalid percent-encoding in {0:?}")]
+    InvalidEncoding(String),
+}
+
+/// The parts of a magnet link this client uses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    /// The v1 info hash from `xt=urn:btih:`.
+    pub info_hash: [u8; 20],
+    /// The suggested name from `dn`.
+    pub display_name: Option<String>,
+    /// Tracker URLs from `tr`, in the order given.
+    pub trackers: Vec<String>,
+    /// Peer addresses (`host:port`) from `x.pe`.
+    pub peers: Vec<String>,
+}
+
+impl FromStr for Magnet {
+    type Err = MagnetError;
+
+    fn from_str(s: &str) -> Result<Self, MagnetError> {
+        let query = s.strip_prefix("magnet:?").ok_or(MagnetError::NotMagnet)?;
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            match key {
+                "xt" => {
+                    // Other `xt` forms, such as v2 `urn:btmh:`, are skipped.
+                    let value = percent_decode(value, false)?;
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        if info_hash.is_none() {
+                            info_hash = Some(parse_info_hash(hash)?);
+                        }
+                    }
+                }
+                "dn" => display_name = Some(percent_decode(value, true)?),
+                // Also accept the numbered `tr.1`, `tr.2`, ... form.
+                "tr" => trackers.push(percent_decode(value, false)?),
+                key if key.starts_with("tr.") => trackers.push(percent_decode(value, false)?),
+                "x.pe" => peers.push(percent_decode(value, false)?),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+            display_name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+impl fmt::Display for Magnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "magnet:?xt=urn:btih:{}", hex::encode(self.info_hash))?;
+        if let Some(name) = &self.display_name {
+            write!(f, "&dn={}", percent_encode(name))?;
+        }
+        for tracker in &self.trackers {
+            write!(f, "&tr={}", percent_encode(tracker))?;
+        }
+        for peer in &self.peers {
+            write!(f, "&x.pe={}", percent_encode(peer))?;
+        }
+        Ok(())
+    }
+}
+
+impl Magnet {
+    /// Each tracker in a tier of its own, tried in the order given.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        self.trackers
+            .iter()
+            .map(|tracker| vec![tracker.clone()])
+            .collect()
+    }
+
+    /// Builds a torrent from the info dictionary fetched from peers, which
+    /// the caller must already have checked against the info hash.
+    pub fn into_torrent(self, info: WithRaw<Info>) -> Torrent {
+        let mut torrent = Torrent {
+            announce: None,
+            announce_list: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            encoding: None,
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
+            piece_layers: BTreeMap::new(),
+            files: OnceLock::new(),
+            info,
+        };
+        torrent.set_trackers(self.tracker_tiers());
+        torrent
+    }
+}
+
+/// Accepts the 40-character hex form and the 32-character base32 form.
+fn parse_info_hash(hash: &str) -> Result<[u8; 20], MagnetError> {
+    let invalid = || MagnetError::InvalidInfoHash(hash.to_string());
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).map_err(|_| invalid())?,
+        32 => base32_decode(hash).ok_or_else(invalid)?,
+        _ => return Err(invalid()),
+    };
+    bytes.try_into().map_err(|_| invalid())
+}
+
+/// Decodes unpadded RFC 4648 base32, ignoring case.
+fn base32_decode(input: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    for by in input.bytes() {
+        let value = match by.to_ascii_uppercase() {
+            by @ b'A'..=b'Z' => by - b'A',
+            by @ b'2'..=b'7' => by - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | u64::from(value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn percent_decode(input: &str, plus_as_space: bool) -> Result<String, MagnetError> {
+    let invalid = || MagnetError::InvalidEncoding(input.to_string());
+    let mut out = Vec::with_capacity(input.len());
+    let mut bytes = input.bytes();
+    while let Some(by) = bytes.next() {
+        match by {
+            b'%' => {
+                let hex = [
+                    bytes.next().ok_or_else(invalid)?,
+                    bytes.next().ok_or_else(invalid)?,
+                ];
+                let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+                out.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            }
+            b'+' if plus_as_space => out.push(b' '),
+            by => out.push(by),
+        }
+    }
+    String::from_utf8(out).map_err(|_| invalid())
+}
+
+fn percent_encode(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for by in input.bytes() {
+        match by {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b':' | b'/' => {
+                out.push(by as char);
+            }
+            by => out.push_str(&format!("%{by:02X}")),
+        }
+    }
+    out
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: &str = "ad42ce8109f54c99613ce38f9b4d87e70f24a165";
+
+    #[test]
+    fn test_parse_hex() {
+        let link = format!("magnet:?xt=urn:btih:{INFO_HASH}&dn=magnet1.gif&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce");
+        let magnet = link.parse::<Magnet>().unwrap();
+        assert_eq!(hex::encode(magnet.info_hash), INFO_HASH);
+        assert_eq!(magnet.display_name.as_deref(), Some("magnet1.gif"));
+        assert_eq!(
+            magnet.trackers,
+            ["http://bittorrent-test-tracker.codecrafters.io/announce"]
+        );
+        assert_eq!(magnet.to_string().parse::<Magnet>().unwrap(), magnet);
+    }
+
+    #[test]
+    fn test_parse_base32() {
+        // The base32 form of INFO_HASH.
+        let link = "magnet:?xt=urn:btih:VVBM5AIJ6VGJSYJ44OHZWTMH44HSJILF&dn=a+b%20c&x.pe=10.0.0.1:6881&tr.1=udp://t/";
+        let magnet = link.parse::<Magnet>().unwrap();
+        assert_eq!(hex::encode(magnet.info_hash), INFO_HASH);
+        assert_eq!(magnet.display_name.as_deref(), Some("a b c"));
+        assert_eq!(magnet.peers, ["10.0.0.1:6881"]);
+        assert_eq!(magnet.trackers, ["udp://t/"]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            "http://example.com".parse::<Magnet>(),
+            Err(MagnetError::NotMagnet)
+        );
+        assert_eq!(
+            "magnet:?dn=x".parse::<Magnet>(),
+            Err(MagnetError::MissingInfoHash)
+        );
+        assert!(matches!(
+            "magnet:?xt=urn:btih:1234".parse::<Magnet>(),
+            Err(MagnetError::InvalidInfoHash(_))
+        ));
+        assert!(matches!(
+            format!("magnet:?xt=urn:btih:{INFO_HASH}&dn=%zz").parse::<Magnet>(),
+            Err(MagnetError::InvalidEncoding(_))
+        ));
+    }
+}
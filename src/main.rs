@@ -1,43 +1,84 @@
 #![warn(clippy::pedantic)]
 
-mod bencode;
 mod cli;
 mod command;
+mod identity;
+mod json;
+mod metadata;
 mod peer;
-mod torrent;
+mod random;
 mod tracker;
+mod webseed;
 
 use std::time::Instant;
 
 use anyhow::Result;
-use bencode::BencodeValue;
-use bittorrent_starter_rust::mini_serde_bencode;
+use bittorrent_starter_rust::{bencode::BencodeValue, create::TorrentBuilder, mini_serde_bencode};
 use clap::Parser;
 use cli::Args;
 use cli::Commands;
+use cli::DecodeFormat;
+use identity::Identity;
 use mini_serde_bencode::from_str;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let start = Instant::now();
     let args = Args::parse();
+    Identity::from_args(args.identity).set_session()?;
 
     match args.cmd {
-        Commands::Decode { value } => {
+        Commands::Decode { value, format } => {
             let bencode_value = from_str::<BencodeValue>(&value)?;
-            println!("{bencode_value}");
+            match format {
+                DecodeFormat::Display => println!("{bencode_value}"),
+                DecodeFormat::Json => println!("{}", json::to_json(&bencode_value)),
+            }
         }
+        Commands::Encode { output_file, json } => command::encode(&json, output_file.as_deref())?,
         Commands::Info { torrent_file } => command::info(&torrent_file)?,
+        Commands::VerifyMeta { torrent_file } => command::verify_meta(&torrent_file)?,
         Commands::Peers { torrent_file } => command::peers(&torrent_file).await?,
-        Commands::Handshake {
-            torrent_file,
-            ip: _,
-        } => command::handshake(&torrent_file).await?,
+        Commands::Scrape { torrent_files } => command::scrape(&torrent_files).await?,
+        Commands::Handshake { torrent_file, ip } => {
+            command::handshake(&torrent_file, ip.as_deref()).await?;
+        }
         Commands::DownloadPiece {
             output_file,
             torrent_file,
             piece_index,
         } => command::download_and_write_piece(&output_file, &torrent_file, piece_index).await?,
+        Commands::Create {
+            output_file,
+            path,
+            trackers,
+            web_seeds,
+            comment,
+            private,
+            piece_length,
+        } => {
+            let mut builder = TorrentBuilder::new(path).private(private);
+            for tracker in trackers {
+                builder = builder.tracker(tracker);
+            }
+            for web_seed in web_seeds {
+                builder = builder.web_seed(web_seed);
+            }
+            if let Some(comment) = comment {
+                builder = builder.comment(comment);
+            }
+            if let Some(piece_length) = piece_length {
+                builder = builder.piece_length(piece_length);
+            }
+            command::create(builder, &output_file)?;
+        }
+        Commands::Edit(args) => command::edit(args)?,
+        Commands::MagnetParse { magnet_link } => command::magnet_parse(&magnet_link)?,
+        Commands::MagnetInfo { magnet_link } => command::magnet_info(&magnet_link).await?,
+        Commands::MagnetDownload {
+            output_file,
+            magnet_link,
+        } => command::magnet_download(&output_file, &magnet_link).await?,
         Commands::Download {
             output_file,
             torrent_file,
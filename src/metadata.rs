@@ -0,0 +1,319 @@
+//! Fetching a torrent's info dictionary from peers with the `ut_metadata`
+//! extension (BEP 9) of the extension protocol (BEP 10).
+
+use anyhow::{anyhow, Result};
+use bittorrent_starter_rust::{
+    magnet::Magnet,
+    mini_serde_bencode::{from_bytes, to_bytes, Deserializer, WithRaw},
+    torrent::{Info, Torrent},
+};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::{collections::BTreeMap, time::Duration};
+use tokio::{net::TcpStream, time::timeout};
+
+use crate::{
+    peer::{Peer, PeerMessage},
+    tracker::discover_peers_for,
+};
+
+// The extended message id we ask peers to use for `ut_metadata` messages.
+const UT_METADATA_ID: u8 = 1;
+const METADATA_PIECE_SIZE: usize = 16 * 1024;
+// Far above any real info dictionary, but keeps a peer from making us
+// buffer an arbitrary amount.
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+// The size of the content is unknown until the metadata arrives; any
+// non-zero value tells trackers we are still downloading.
+const UNKNOWN_LEFT: u64 = 999;
+// How long one peer gets to deliver the whole info dictionary before we move
+// on to the next.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The payload of the extended handshake, message id 0.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ExtensionHandshake {
+    /// Extension names mapped to the message ids the sender wants to receive
+    /// them under; 0 means disabled.
+    #[serde(default)]
+    m: BTreeMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata_size: Option<usize>,
+}
+
+/// The dictionary at the start of every `ut_metadata` message. Data messages
+/// carry the piece's bytes after it.
+#[derive(Debug, Serialize, Deserialize)]
+struct MetadataMessage {
+    /// 0 for a request, 1 for data, 2 for a reject.
+    msg_type: u8,
+    piece: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+/// The peers found for a magnet link, kept apart by where they came from.
+#[derive(Debug, Default)]
+pub struct MagnetPeers {
+    /// Returned by the link's trackers.
+    pub tracked: Vec<Peer>,
+    /// Listed in the link itself (`x.pe`).
+    pub listed: Vec<Peer>,
+}
+
+impl MagnetPeers {
+    /// Every peer, those from trackers first.
+    pub fn all(&self) -> Vec<Peer> {
+        self.tracked.iter().chain(&self.listed).copied().collect()
+    }
+
+    /// The peers to download the content from. A private torrent (BEP 27)
+    /// must only use peers from its trackers, so the link's own are dropped.
+    pub fn for_content(self, private: bool) -> Vec<Peer> {
+        if private {
+            self.tracked
+        } else {
+            self.all()
+        }
+    }
+}
+
+/// The peers to ask for a magnet's metadata: those its trackers return and
+/// those listed in the link itself.
+pub async fn magnet_peers(magnet: &Magnet) -> Result<MagnetPeers> {
+    let tracked = if magnet.trackers.is_empty() {
+        Vec::new()
+    } else {
+        match discover_peers_for(
+            magnet.info_hash.to_vec(),
+            magnet.tracker_tiers(),
+            UNKNOWN_LEFT,
+        )
+        .await
+        {
+            Ok(peers) => peers,
+            Err(e) if !magnet.peers.is_empty() => {
+                println!("No peers from trackers: {e}");
+                Vec::new()
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    let mut listed = Vec::new();
+    for peer in &magnet.peers {
+        match peer.parse::<Peer>() {
+            Ok(peer) => listed.push(peer),
+            Err(e) => println!("Skipping peer: {e}"),
+        }
+    }
+    Ok(MagnetPeers { tracked, listed })
+}
+
+/// Asks each peer in turn for the info dictionary until one delivers a copy
+/// matching the magnet's info hash.
+pub async fn fetch_torrent(magnet: Magnet, peers: &[Peer]) -> Result<Torrent> {
+    fetch_torrent_within(magnet, peers, PEER_TIMEOUT).await
+}
+
+/// Like [`fetch_torrent`], giving each peer `limit` to finish.
+async fn fetch_torrent_within(magnet: Magnet, peers: &[Peer], limit: Duration) -> Result<Torrent> {
+    for peer in peers {
+        match timeout(limit, fetch_info(*peer, &magnet.info_hash)).await {
+            Ok(Ok(info)) => return Ok(magnet.into_torrent(info)),
+            Ok(Err(e)) => println!("Failed to fetch metadata from peer {peer}: {e}"),
+            Err(_) => println!("Peer {peer} timed out sending the metadata"),
+        }
+    }
+    Err(anyhow!("No peer provided the metadata"))
+}
+
+async fn fetch_info(peer: Peer, info_hash: &[u8; 20]) -> Result<WithRaw<Info>> {
+    let (mut socket, handshake) = peer.handshake(info_hash).await?;
+    if !handshake.supports_extensions() {
+        return Err(anyhow!("Peer does not support the extension protocol"));
+    }
+
+    let ours = ExtensionHandshake {
+        m: BTreeMap::from([("ut_metadata".to_string(), i64::from(UT_METADATA_ID))]),
+        metadata_size: None,
+    };
+    PeerMessage::Extended(0, to_bytes(&ours)?)
+        .write_to(&mut socket)
+        .await?;
+    let theirs: ExtensionHandshake = from_bytes(&receive_extended(&mut socket, 0).await?)?;
+    let their_id = theirs
+        .m
+        .get("ut_metadata")
+        .and_then(|id| u8::try_from(*id).ok())
+        .filter(|id| *id != 0)
+        .ok_or(anyhow!("Peer does not support ut_metadata"))?;
+    let size = theirs
+        .metadata_size
+        .ok_or(anyhow!("Peer did not announce the metadata size"))?;
+    if size == 0 || size > MAX_METADATA_SIZE {
+        return Err(anyhow!("Invalid metadata size: {size}"));
+    }
+
+    let mut metadata = Vec::with_capacity(size);
+    for piece in 0..u32::try_from(size.div_ceil(METADATA_PIECE_SIZE))? {
+        let request = MetadataMessage {
+            msg_type: 0,
+            piece,
+            total_size: None,
+        };
+        PeerMessage::Extended(their_id, to_bytes(&request)?)
+            .write_to(&mut socket)
+            .await?;
+
+        let payload = receive_extended(&mut socket, UT_METADATA_ID).await?;
+        let mut deserializer = Deserializer::from_bytes(&payload);
+        let message = MetadataMessage::deserialize(&mut deserializer)?;
+        let data = &payload[deserializer.byte_offset()..];
+        match message.msg_type {
+            1 if message.piece == piece => metadata.extend_from_slice(data),
+            2 => return Err(anyhow!("Peer rejected the request for piece {piece}")),
+            _ => return Err(anyhow!("Unexpected ut_metadata message: {message:?}")),
+        }
+    }
+
+    if metadata.len() != size {
+        return Err(anyhow!(
+            "Received {} bytes of metadata instead of {size}",
+            metadata.len()
+        ));
+    }
+    if Sha1::digest(&metadata).as_slice() != info_hash {
+        return Err(anyhow!("Metadata does not match the info hash"));
+    }
+    Ok(from_bytes(&metadata)?)
+}
+
+/// Waits for the next extended message with `id`, skipping everything else
+/// the peer sends meanwhile, such as its bitfield.
+async fn receive_extended(socket: &mut TcpStream, id: u8) -> Result<Vec<u8>> {
+    loop {
+        if let PeerMessage::Extended(message_id, payload) = PeerMessage::read_from(socket).await? {
+            if message_id == id {
+                return Ok(payload);
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // Serves `metadata` over ut_metadata to one connection, as a seeder would.
+    async fn serve_metadata(listener: TcpListener, metadata: Vec<u8>) -> Result<()> {
+        let (mut socket, _) = listener.accept().await?;
+        let mut handshake = [0; 68];
+        socket.read_exact(&mut handshake).await?;
+        handshake[25] |= 0x10;
+        socket.write_all(&handshake).await?;
+
+        PeerMessage::Bitfield(vec![0xff])
+            .write_to(&mut socket)
+            .await?;
+        let _ = receive_extended(&mut socket, 0).await?;
+        let theirs = ExtensionHandshake {
+            m: BTreeMap::from([("ut_metadata".to_string(), 3)]),
+            metadata_size: Some(metadata.len()),
+        };
+        PeerMessage::Extended(0, to_bytes(&theirs)?)
+            .write_to(&mut socket)
+            .await?;
+
+        for chunk in metadata.chunks(METADATA_PIECE_SIZE) {
+            let request: MetadataMessage = from_bytes(&receive_extended(&mut socket, 3).await?)?;
+            assert_eq!(request.msg_type, 0);
+            let mut payload = to_bytes(&MetadataMessage {
+                msg_type: 1,
+                piece: request.piece,
+                total_size: Some(metadata.len()),
+            })?;
+            payload.extend_from_slice(chunk);
+            PeerMessage::Extended(UT_METADATA_ID, payload)
+                .write_to(&mut socket)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Info dictionary large enough to span two metadata pieces.
+    fn metadata() -> Vec<u8> {
+        let pieces = "x".repeat(20 * 1000);
+        format!(
+            "d6:lengthi5e4:name4:spam12:piece lengthi16384e6:pieces{}:{pieces}e",
+            pieces.len()
+        )
+        .into_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_fetch_info() {
+        let metadata = metadata();
+        let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve_metadata(listener, metadata.clone()));
+
+        let info = fetch_info(Peer::new([127, 0, 0, 1], port), &info_hash)
+            .await
+            .unwrap();
+        assert_eq!(info.name, "spam");
+        assert_eq!(info.raw().get(), metadata);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_silent_peer() {
+        let metadata = metadata();
+        let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+        let magnet = format!("magnet:?xt=urn:btih:{}", hex::encode(info_hash))
+            .parse::<Magnet>()
+            .unwrap();
+
+        // Accepts the connection but never answers the handshake.
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_port = silent.local_addr().unwrap().port();
+        let _silent = tokio::spawn(async move {
+            let (socket, _) = silent.accept().await.unwrap();
+            std::future::pending::<()>().await;
+            drop(socket);
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve_metadata(listener, metadata));
+
+        let peers = [
+            Peer::new([127, 0, 0, 1], silent_port),
+            Peer::new([127, 0, 0, 1], port),
+        ];
+        let torrent = fetch_torrent_within(magnet, &peers, Duration::from_millis(200))
+            .await
+            .unwrap();
+        assert_eq!(torrent.info.name, "spam");
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_private_content_peers() {
+        let peers = MagnetPeers {
+            tracked: vec!["10.0.0.1:6881".parse().unwrap()],
+            listed: vec!["10.0.0.2:6881".parse().unwrap()],
+        };
+        assert_eq!(peers.all().len(), 2);
+        assert_eq!(
+            peers.for_content(true),
+            ["10.0.0.1:6881".parse::<Peer>().unwrap()]
+        );
+    }
+}
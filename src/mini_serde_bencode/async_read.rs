@@ -0,0 +1,240 @@
+use serde::de::DeserializeOwned;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::{
+    de::{Deserializer, Limits},
+    error::{Error, Path, Position, Result},
+};
+
+/// Reads exactly one bencode value from `reader` and returns its bytes,
+/// leaving anything after it unread.
+///
+/// The structure is checked as the bytes arrive, so malformed integers,
+/// oversized strings and values, and overly deep nesting are rejected before
+/// they are buffered. Wrap unbuffered readers such as sockets in a
+/// [`tokio::io::BufReader`].
+pub async fn read_value<R>(reader: &mut R, limits: Limits) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+    let mut depth = 0;
+    loop {
+        match next_byte(reader, &mut buf, limits).await? {
+            b'i' => read_integer(reader, &mut buf, limits).await?,
+            by @ b'0'..=b'9' => {
+                let start = buf.len() - 1;
+                let mut len = usize::from(by - b'0');
+                loop {
+                    match next_byte(reader, &mut buf, limits).await? {
+                        b':' => break,
+                        by @ b'0'..=b'9' => {
+                            len = len
+                                .checked_mul(10)
+                                .and_then(|len| len.checked_add(usize::from(by - b'0')))
+                                .ok_or_else(|| Error::InvalidLength(position_at(start)))?;
+                        }
+                        _ => return Err(Error::InvalidLength(position_at(start))),
+                    }
+                }
+                if len > limits.max_string_length {
+                    return Err(Error::StringTooLong(position_at(start)));
+                }
+                if buf.len().saturating_add(len) > limits.max_value_length {
+                    return Err(Error::ValueTooLong(position_at(start)));
+                }
+                let read = reader
+                    .take(len as u64)
+                    .read_to_end(&mut buf)
+                    .await
+                    .map_err(Error::Io)?;
+                if read < len {
+                    return Err(Error::UnexpectedEof(position(&buf)));
+                }
+            }
+            b'l' | b'd' => {
+                if depth == limits.max_depth {
+                    return Err(Error::DepthLimitExceeded(position_at(buf.len() - 1)));
+                }
+                depth += 1;
+                continue;
+            }
+            b'e' if depth > 0 => depth -= 1,
+            by => {
+                return Err(Error::TypeMismatch {
+                    expected: "bencode value",
+                    found: by,
+                    position: position_at(buf.len() - 1),
+                })
+            }
+        }
+
+        if depth == 0 {
+            return Ok(buf);
+        }
+    }
+}
+
+/// Reads one bencode value from `reader` with [`read_value`] and deserializes
+/// it.
+pub async fn from_async_reader<R, T>(reader: &mut R, limits: Limits) -> Result<T>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let buf = read_value(reader, limits).await?;
+    let mut deserializer = Deserializer::from_bytes(&buf).with_limits(limits);
+    let t = T::deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(t)
+}
+
+// Reads the rest of an integer after its `i`: an optional minus sign, then
+// at least one digit, then `e`. Leading zeros are left to the deserializer,
+// which rejects them only in strict mode.
+async fn read_integer<R>(reader: &mut R, buf: &mut Vec<u8>, limits: Limits) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let start = buf.len();
+    let mut digits = 0;
+    loop {
+        match next_byte(reader, buf, limits).await? {
+            b'-' if buf.len() - 1 == start => {}
+            b'0'..=b'9' if digits < limits.max_string_length => digits += 1,
+            b'e' if digits > 0 => return Ok(()),
+            _ => return Err(Error::InvalidInteger(position_at(buf.len() - 1))),
+        }
+    }
+}
+
+async fn next_byte<R>(reader: &mut R, buf: &mut Vec<u8>, limits: Limits) -> Result<u8>
+where
+    R: AsyncRead + Unpin,
+{
+    if buf.len() >= limits.max_value_length {
+        return Err(Error::ValueTooLong(position(buf)));
+    }
+    match reader.read_u8().await {
+        Ok(by) => {
+            buf.push(by);
+            Ok(by)
+        }
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(Error::UnexpectedEof(position(buf)))
+        }
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+fn position(buf: &[u8]) -> Position {
+    position_at(buf.len())
+}
+
+fn position_at(offset: usize) -> Position {
+    Position {
+        offset,
+        path: Path::default(),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_bytes::ByteBuf;
+
+    #[tokio::test]
+    async fn test_read_value() {
+        let mut input: &[u8] = b"d4:spaml1:ai5eeei6e";
+        let value = read_value(&mut input, Limits::default()).await.unwrap();
+        assert_eq!(value, b"d4:spaml1:ai5eee");
+        assert_eq!(input, b"i6e");
+    }
+
+    #[tokio::test]
+    async fn test_from_async_reader() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Response {
+            interval: i64,
+            peers: ByteBuf,
+        }
+
+        let mut input: &[u8] = b"d8:intervali1800e5:peers6:\x7f\0\0\x01\x1a\xe1e";
+        let response: Response = from_async_reader(&mut input, Limits::default())
+            .await
+            .unwrap();
+        assert_eq!(response.interval, 1800);
+        assert_eq!(*response.peers, [127, 0, 0, 1, 0x1a, 0xe1]);
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let limits = Limits {
+            max_depth: 2,
+            max_string_length: 4,
+            max_value_length: 12,
+        };
+
+        let mut input: &[u8] = b"llleee";
+        let err = read_value(&mut input, limits).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::DepthLimitExceeded(Position { offset: 2, .. })
+        ));
+
+        let mut input: &[u8] = b"1000000000:";
+        let err = read_value(&mut input, limits).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::StringTooLong(Position { offset: 0, .. })
+        ));
+
+        let mut input: &[u8] = b"4:spa";
+        let err = read_value(&mut input, limits).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::UnexpectedEof(Position { offset: 5, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_value_limit() {
+        let limits = Limits {
+            max_value_length: 12,
+            ..Limits::default()
+        };
+
+        let mut input: &[u8] = b"l4:spam4:eggse";
+        let err = read_value(&mut input, limits).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::ValueTooLong(Position { offset: 7, .. })
+        ));
+
+        let mut input: &[u8] = b"li1ei2ei3ei4ee";
+        let err = read_value(&mut input, limits).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::ValueTooLong(Position { offset: 12, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_integers() {
+        for input in [&b"i1-2e"[..], b"ie", b"i-e", b"i--1e", b"i1.5e"] {
+            let mut input = input;
+            let err = read_value(&mut input, Limits::default()).await.unwrap_err();
+            assert!(matches!(err, Error::InvalidInteger(_)), "{err}");
+        }
+
+        let mut input: &[u8] = b"i-12e";
+        assert_eq!(
+            read_value(&mut input, Limits::default()).await.unwrap(),
+            b"i-12e"
+        );
+    }
+}
@@ -1,23 +1,108 @@
-use crate::mini_serde_bencode::error::{Error, Result};
+use crate::mini_serde_bencode::{
+    error::{Error, Path, PathSegment, Position, Result},
+    raw,
+    read::{IoRead, Read, Reference, SliceRead},
+};
 use serde::{
-    de::{self, EnumAccess, VariantAccess},
+    de::{
+        self,
+        value::{
+            BorrowedBytesDeserializer, BorrowedStrDeserializer, BytesDeserializer, StrDeserializer,
+            StringDeserializer,
+        },
+        DeserializeOwned, EnumAccess, VariantAccess,
+    },
     forward_to_deserialize_any, Deserialize,
 };
-use std::ops::{AddAssign, MulAssign, Neg};
+use std::{cmp::Ordering, io};
 
-pub struct Deserializer<'de> {
-    input: &'de [u8],
+/// Bounds on the input the deserializer is willing to accept, to keep hostile
+/// input from exhausting the stack or memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// How many lists and dictionaries may be nested inside each other.
+    pub max_depth: usize,
+    /// The longest byte string accepted, in bytes.
+    pub max_string_length: usize,
+    /// The longest value [`read_value`](super::read_value) buffers, in
+    /// bytes.
+    pub max_value_length: usize,
 }
 
-impl<'de> Deserializer<'de> {
-    pub fn from_str(input: &'de str) -> Self {
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_depth: 256,
+            max_string_length: 64 * 1024 * 1024,
+            max_value_length: 64 * 1024 * 1024,
+        }
+    }
+}
+
+pub struct Deserializer<R> {
+    read: R,
+    scratch: Vec<u8>,
+    path: Vec<PathSegment>,
+    depth: usize,
+    limits: Limits,
+    strict: bool,
+}
+
+impl<'de, R: Read<'de>> Deserializer<R> {
+    pub fn new(read: R) -> Self {
         Deserializer {
-            input: input.as_bytes(),
+            read,
+            scratch: Vec::new(),
+            path: Vec::new(),
+            depth: 0,
+            limits: Limits::default(),
+            strict: false,
         }
     }
 
+    pub fn with_limits(self, limits: Limits) -> Self {
+        Deserializer { limits, ..self }
+    }
+
+    /// The number of input bytes consumed so far.
+    pub fn byte_offset(&self) -> usize {
+        self.read.offset()
+    }
+
+    /// Fails with [`Error::TrailingBytes`] unless the whole input was consumed.
+    pub fn end(&mut self) -> Result<()> {
+        match self.read.peek() {
+            Ok(None) => Ok(()),
+            Ok(Some(_)) => Err(Error::TrailingBytes(self.position())),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+}
+
+impl<'de> Deserializer<SliceRead<'de>> {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(input: &'de str) -> Self {
+        Self::from_bytes(input.as_bytes())
+    }
+
     pub fn from_bytes(input: &'de [u8]) -> Self {
-        Deserializer { input }
+        Deserializer::new(SliceRead::new(input))
+    }
+
+    /// Like [`Deserializer::from_bytes`], but only accepts canonical bencode:
+    /// integers and lengths without leading zeros, no `i-0e`, and dictionary
+    /// keys in strictly ascending byte order.
+    pub fn strict(input: &'de [u8]) -> Self {
+        Deserializer {
+            strict: true,
+            ..Self::from_bytes(input)
+        }
+    }
+}
+
+impl<R: io::Read> Deserializer<IoRead<R>> {
+    pub fn from_reader(reader: R) -> Self {
+        Deserializer::new(IoRead::new(reader))
     }
 }
 
@@ -25,109 +110,263 @@ pub fn from_str<'a, T>(s: &'a str) -> Result<T>
 where
     T: Deserialize<'a>,
 {
-    let mut deserializer = Deserializer::from_str(s);
-    let t = T::deserialize(&mut deserializer)?;
-    if deserializer.input.is_empty() {
-        Ok(t)
-    } else {
-        Err(Error::Message("trailing bytes".to_string()))
-    }
+    from_bytes(s.as_bytes())
 }
 
 pub fn from_bytes<'a, T>(b: &'a [u8]) -> Result<T>
 where
     T: Deserialize<'a>,
 {
-    let mut deserializer = Deserializer::from_bytes(b);
-    let t = T::deserialize(&mut deserializer)?;
-    if deserializer.input.is_empty() {
-        Ok(t)
-    } else {
-        Err(Error::Message("trailing bytes".to_string()))
-    }
+    deserialize(Deserializer::from_bytes(b))
 }
 
-impl<'de> Deserializer<'de> {
-    // Look at the first character in the input without consuming it.
-    fn peek_byte(&mut self) -> Result<u8> {
-        self.input
-            .iter()
-            .next()
-            .copied()
-            .ok_or(Error::Message("eof".to_string()))
-    }
+/// Deserializes `b`, rejecting any input that is not canonical bencode.
+pub fn from_bytes_strict<'a, T>(b: &'a [u8]) -> Result<T>
+where
+    T: Deserialize<'a>,
+{
+    deserialize(Deserializer::strict(b))
+}
 
-    // Consume the first character in the input.
-    fn next_byte(&mut self) -> Result<u8> {
-        let by = self.peek_byte()?;
-        self.input = &self.input[1..];
-        Ok(by)
+/// Deserializes a single value from `reader` as it arrives, within the
+/// default [`Limits`]. The whole reader must be consumed by the value.
+pub fn from_reader<R, T>(reader: R) -> Result<T>
+where
+    R: io::Read,
+    T: DeserializeOwned,
+{
+    deserialize(Deserializer::from_reader(reader))
+}
+
+fn deserialize<'de, R, T>(mut deserializer: Deserializer<R>) -> Result<T>
+where
+    R: Read<'de>,
+    T: Deserialize<'de>,
+{
+    let t = T::deserialize(&mut deserializer).map_err(|e| {
+        e.locate(|| Position {
+            offset: 0,
+            path: Path::default(),
+        })
+    })?;
+    deserializer.end()?;
+    Ok(t)
+}
+
+// An integer as read from the input, in the narrowest form that holds it.
+enum Integer {
+    Signed(i64),
+    Unsigned(u64),
+    Signed128(i128),
+    Unsigned128(u128),
+    Big(String),
+}
+
+impl Integer {
+    fn from_digits(digits: String) -> Self {
+        if let Ok(int) = digits.parse() {
+            Integer::Signed(int)
+        } else if let Ok(int) = digits.parse() {
+            Integer::Unsigned(int)
+        } else if let Ok(int) = digits.parse() {
+            Integer::Signed128(int)
+        } else if let Ok(int) = digits.parse() {
+            Integer::Unsigned128(int)
+        } else {
+            Integer::Big(digits)
+        }
     }
 
-    fn parse_signed<T>(&mut self) -> Result<T>
+    fn visit<'de, V>(self, visitor: V) -> Result<V::Value>
     where
-        T: Neg<Output = T> + AddAssign<T> + MulAssign<T> + From<i8>,
+        V: de::Visitor<'de>,
     {
-        match self.next_byte()? {
-            b'i' => {}
-            _ => {
-                return Err(Error::Message("expected i".to_string()));
+        match self {
+            Integer::Signed(int) => visitor.visit_i64(int),
+            Integer::Unsigned(int) => visitor.visit_u64(int),
+            Integer::Signed128(int) => visitor.visit_i128(int),
+            Integer::Unsigned128(int) => visitor.visit_u128(int),
+            // Handed over as a newtype wrapping the digits, which
+            // `BigInteger` and visitors that know about it can unwrap.
+            Integer::Big(digits) => {
+                visitor.visit_newtype_struct(StringDeserializer::<Error>::new(digits))
             }
-        };
+        }
+    }
+}
 
-        let is_neg = matches!(self.peek_byte()?, b'-');
+fn position_at(path: &[PathSegment], offset: usize) -> Position {
+    Position {
+        offset,
+        path: Path(path.to_vec()),
+    }
+}
 
-        if is_neg {
-            self.next_byte()?;
+impl<'de, R: Read<'de>> Deserializer<R> {
+    fn position(&self) -> Position {
+        self.position_at(self.read.offset())
+    }
+
+    fn position_at(&self, offset: usize) -> Position {
+        position_at(&self.path, offset)
+    }
+
+    fn type_mismatch(&self, expected: &'static str, found: u8) -> Error {
+        Error::TypeMismatch {
+            expected,
+            found,
+            position: self.position(),
         }
+    }
 
-        let mut int = match self.next_byte()? {
-            by @ b'0'..=b'9' => T::from((by - b'0') as i8),
-            _ => {
-                return Err(Error::Message("invalid int".to_string()));
+    // Run `f` with `segment` appended to the current path, so that any error
+    // it raises points at the nested value.
+    fn nested<T>(
+        &mut self,
+        segment: PathSegment,
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let start = self.read.offset();
+        self.path.push(segment);
+        let result = f(self).map_err(|e| e.locate(|| self.position_at(start)));
+        self.path.pop();
+        result
+    }
+
+    // Enter a list or dictionary, enforcing the nesting limit.
+    fn descend(&mut self) -> Result<()> {
+        if self.depth == self.limits.max_depth {
+            return Err(Error::DepthLimitExceeded(self.position()));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    // Look at the first character in the input without consuming it.
+    fn peek_byte(&mut self) -> Result<u8> {
+        match self.read.peek() {
+            Ok(Some(by)) => Ok(by),
+            Ok(None) => Err(Error::UnexpectedEof(self.position())),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+
+    // Consume `expected`, or fail without consuming anything.
+    fn expect_byte(&mut self, expected: u8, what: &'static str) -> Result<()> {
+        match self.peek_byte()? {
+            by if by == expected => {
+                self.read.discard();
+                Ok(())
             }
-        };
+            by => Err(self.type_mismatch(what, by)),
+        }
+    }
+
+    fn parse_integer(&mut self) -> Result<Integer> {
+        self.expect_byte(b'i', "integer")?;
+
+        let start = self.read.offset();
+        let mut digits = String::new();
+        if self.peek_byte()? == b'-' {
+            self.read.discard();
+            digits.push('-');
+        }
+        if !self.peek_byte()?.is_ascii_digit() {
+            return Err(Error::InvalidInteger(self.position()));
+        }
 
         loop {
-            match self.input.iter().next() {
-                Some(by @ b'0'..=b'9') => {
-                    self.input = &self.input[1..];
-                    int *= T::from(10);
-                    int += T::from((by - b'0') as i8);
+            match self.peek_byte()? {
+                by @ b'0'..=b'9' if digits.len() < self.limits.max_string_length => {
+                    self.read.discard();
+                    digits.push(char::from(by));
                 }
-                Some(b'e') => {
-                    self.input = &self.input[1..];
-                    if is_neg {
-                        int *= T::from(-1);
-                        return Ok(int);
-                    } else {
-                        return Ok(int);
-                    }
+                b'e' => {
+                    self.read.discard();
+                    break;
                 }
                 _ => {
-                    return Err(Error::Message("expected e".to_string()));
+                    return Err(Error::InvalidInteger(self.position()));
                 }
             }
         }
+
+        // Canonical integers have no leading zeros and zero is never negative.
+        let magnitude = digits.trim_start_matches('-');
+        if self.strict
+            && magnitude.starts_with('0')
+            && (magnitude.len() > 1 || magnitude.len() < digits.len())
+        {
+            return Err(Error::InvalidInteger(
+                self.position_at(start + digits.len() - magnitude.len()),
+            ));
+        }
+
+        Ok(Integer::from_digits(digits))
     }
 
-    fn parse_bytes(&mut self) -> Result<Vec<u8>> {
-        if let Some(colon_index) = self.input.iter().position(|b| b == &b':') {
-            let len_str = std::str::from_utf8(&self.input[..colon_index])
-                .map_err(|_| Error::Message("invalid utf8".to_string()))?;
-            let len: usize = len_str
-                .parse()
-                .map_err(|_| Error::Message("invalid len".to_string()))?;
-            let bytes = self.input[colon_index + 1..colon_index + 1 + len].to_vec();
-            self.input = &self.input[colon_index + 1 + len..];
-            Ok(bytes)
-        } else {
-            Err(Error::Message("expected :".to_string()))
+    // Parse an integer for a visitor that asked for a specific integer type,
+    // which cannot take one beyond 128 bits.
+    fn deserialize_sized_integer<V>(&mut self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        let start = self.read.offset();
+        match self.parse_integer()? {
+            Integer::Big(_) => Err(Error::IntegerOutOfRange(self.position_at(start))),
+            int => int.visit(visitor),
         }
     }
+
+    // Parse the `<length>:` prefix of a byte string.
+    fn parse_length(&mut self) -> Result<usize> {
+        let start = self.read.offset();
+        let first = self.peek_byte()?;
+        if !first.is_ascii_digit() {
+            return Err(self.type_mismatch("byte string", first));
+        }
+
+        let mut len: usize = 0;
+        loop {
+            match self.peek_byte()? {
+                by @ b'0'..=b'9' => {
+                    self.read.discard();
+                    len = len
+                        .checked_mul(10)
+                        .and_then(|len| len.checked_add(usize::from(by - b'0')))
+                        .ok_or_else(|| Error::InvalidLength(self.position_at(start)))?;
+                }
+                b':' => {
+                    if self.strict && first == b'0' && self.read.offset() - start > 1 {
+                        return Err(Error::InvalidLength(self.position_at(start)));
+                    }
+                    self.read.discard();
+                    break;
+                }
+                _ => return Err(Error::InvalidLength(self.position_at(start))),
+            }
+        }
+
+        if len > self.limits.max_string_length {
+            return Err(Error::StringTooLong(self.position_at(start)));
+        }
+        Ok(len)
+    }
+
+    // Parse a byte string and hand it to `f`, borrowed from the input where
+    // the source allows it.
+    fn parse_bytes<T>(&mut self, f: impl FnOnce(Reference<'de, '_>) -> Result<T>) -> Result<T> {
+        let len = self.parse_length()?;
+        let bytes = match self.read.read_bytes(len, &mut self.scratch) {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => return Err(Error::UnexpectedEof(self.position())),
+            Err(e) => return Err(Error::Io(e)),
+        };
+        f(bytes)
+    }
 }
 
-impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
+impl<'de, R: Read<'de>> de::Deserializer<'de> for &mut Deserializer<R> {
     type Error = Error;
 
     fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
@@ -135,68 +374,217 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
         V: de::Visitor<'de>,
     {
         match self.peek_byte()? {
-            b'0'..=b'9' => self.deserialize_byte_buf(visitor),
-            b'i' => self.deserialize_i64(visitor),
+            b'0'..=b'9' => self.deserialize_bytes(visitor),
+            b'i' => self.parse_integer()?.visit(visitor),
             b'l' => self.deserialize_seq(visitor),
             b'd' => self.deserialize_map(visitor),
-            _ => Err(Error::Message("invalid type".to_string())),
+            by => Err(self.type_mismatch("bencode value", by)),
         }
     }
 
     forward_to_deserialize_any! {
-        bool i8 i16 i32 i128 u8 u16 u32 u64 u128 f32 f64 char str string
-        bytes option struct tuple tuple_struct newtype_struct unit unit_struct
+        f32 f64 char struct tuple tuple_struct
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        let start = self.read.offset();
+        match self.parse_integer()? {
+            Integer::Signed(0) => visitor.visit_bool(false),
+            Integer::Signed(1) => visitor.visit_bool(true),
+            _ => Err(Error::InvalidInteger(self.position_at(start))),
+        }
+    }
+
+    // A value that is present is always `Some`; `None` is a missing key.
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        let start = self.read.offset();
+        if self.parse_bytes(|bytes| Ok(bytes.is_empty()))? {
+            visitor.visit_unit()
+        } else {
+            Err(Error::InvalidLength(self.position_at(start)))
+        }
+    }
+
+    fn deserialize_unit_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        if name != raw::TOKEN {
+            return visitor.visit_newtype_struct(self);
+        }
+
+        // Skip over the value and hand its exact bytes to the `RawValue`.
+        self.read.begin_raw();
+        de::Deserializer::deserialize_ignored_any(&mut *self, de::IgnoredAny)?;
+        match self.read.end_raw() {
+            Reference::Borrowed(bytes) => visitor.visit_borrowed_bytes(bytes),
+            Reference::Copied(bytes) => visitor.visit_bytes(bytes),
+        }
+    }
+
+    fn deserialize_i8<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_sized_integer(visitor)
+    }
+
+    fn deserialize_i16<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_sized_integer(visitor)
+    }
+
+    fn deserialize_i32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_sized_integer(visitor)
     }
 
     fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        visitor.visit_i64(self.parse_signed()?)
+        self.deserialize_sized_integer(visitor)
+    }
+
+    fn deserialize_i128<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_sized_integer(visitor)
+    }
+
+    fn deserialize_u8<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_sized_integer(visitor)
+    }
+
+    fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_sized_integer(visitor)
+    }
+
+    fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_sized_integer(visitor)
+    }
+
+    fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_sized_integer(visitor)
+    }
+
+    fn deserialize_u128<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_sized_integer(visitor)
+    }
+
+    // Byte strings are handed out as slices of the input; visitors that need
+    // ownership copy them themselves.
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.parse_bytes(|bytes| match bytes {
+            Reference::Borrowed(bytes) => visitor.visit_borrowed_bytes(bytes),
+            Reference::Copied(bytes) => visitor.visit_bytes(bytes),
+        })
     }
 
     fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        visitor.visit_byte_buf(self.parse_bytes()?)
+        self.deserialize_bytes(visitor)
     }
 
-    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
+    // Bencode strings carry no encoding, so only valid UTF-8 is offered as a
+    // `str`; anything else is left to the visitor as raw bytes.
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        if self.next_byte()? == b'l' {
-            // Give the visitor access to each element of the sequence.
-            let value = visitor.visit_seq(Seq::new(self))?;
+        self.parse_bytes(|bytes| match bytes {
+            Reference::Borrowed(bytes) => match std::str::from_utf8(bytes) {
+                Ok(s) => visitor.visit_borrowed_str(s),
+                Err(_) => visitor.visit_borrowed_bytes(bytes),
+            },
+            Reference::Copied(bytes) => match std::str::from_utf8(bytes) {
+                Ok(s) => visitor.visit_str(s),
+                Err(_) => visitor.visit_bytes(bytes),
+            },
+        })
+    }
 
-            // Parse the closing bracket of the sequence.
-            if self.next_byte()? == b'e' {
-                Ok(value)
-            } else {
-                Err(Error::Message("expected e".to_string()))
-            }
-        } else {
-            Err(Error::Message("expected l".to_string()))
-        }
+    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.descend()?;
+        self.expect_byte(b'l', "list")?;
+        // Give the visitor access to each element of the sequence.
+        let value = visitor.visit_seq(Seq::new(self))?;
+        // Parse the closing bracket of the sequence.
+        self.expect_byte(b'e', "end of list")?;
+        self.depth -= 1;
+        Ok(value)
     }
 
     fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
     where
         V: de::Visitor<'de>,
     {
-        if self.next_byte()? == b'd' {
-            // Give the visitor access to each element of the sequence.
-            let value = visitor.visit_map(Map::new(self))?;
-            // Parse the closing bracket of the sequence.
-            if self.next_byte()? == b'e' {
-                Ok(value)
-            } else {
-                Err(Error::Message("expected e".to_string()))
-            }
-        } else {
-            Err(Error::Message("expected d".to_string()))
-        }
+        let start = self.read.offset();
+        self.descend()?;
+        self.expect_byte(b'd', "dictionary")?;
+        // Give the visitor access to each entry of the dictionary.
+        let value = visitor
+            .visit_map(Map::new(self))
+            .map_err(|e| e.locate(|| self.position_at(start)))?;
+        // Parse the closing bracket of the dictionary.
+        self.expect_byte(b'e', "end of dictionary")?;
+        self.depth -= 1;
+        Ok(value)
     }
 
     fn deserialize_enum<V>(
@@ -208,18 +596,34 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     where
         V: de::Visitor<'de>,
     {
-        if self.next_byte()? == b'd' {
-            // Visit a newtype variant, tuple variant, or struct variant.
-            let value = visitor.visit_enum(Enum::new(self))?;
-            // Parse the matching close brace.
-            if self.next_byte()? == b'e' {
-                Ok(value)
-            } else {
-                Err(Error::Message("expected e".to_string()))
-            }
-        } else {
-            Err(Error::Message("expected d".to_string()))
+        // Unit variants are plain strings.
+        if self.peek_byte()?.is_ascii_digit() {
+            return self.parse_bytes(|bytes| match bytes {
+                Reference::Borrowed(bytes) => match std::str::from_utf8(bytes) {
+                    Ok(s) => visitor.visit_enum(BorrowedStrDeserializer::new(s)),
+                    Err(_) => Err(de::Error::invalid_value(
+                        de::Unexpected::Bytes(bytes),
+                        &"a variant name",
+                    )),
+                },
+                Reference::Copied(bytes) => match std::str::from_utf8(bytes) {
+                    Ok(s) => visitor.visit_enum(StrDeserializer::new(s)),
+                    Err(_) => Err(de::Error::invalid_value(
+                        de::Unexpected::Bytes(bytes),
+                        &"a variant name",
+                    )),
+                },
+            });
         }
+
+        self.descend()?;
+        self.expect_byte(b'd', "dictionary")?;
+        // Visit a newtype variant, tuple variant, or struct variant.
+        let value = visitor.visit_enum(Enum::new(self))?;
+        // Parse the matching close brace.
+        self.expect_byte(b'e', "end of dictionary")?;
+        self.depth -= 1;
+        Ok(value)
     }
 
     fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value>
@@ -237,7 +641,7 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
     }
 }
 
-impl<'de, 'a> de::SeqAccess<'de> for Seq<'a, 'de> {
+impl<'de, R: Read<'de>> de::SeqAccess<'de> for Seq<'_, R> {
     type Error = Error;
 
     fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
@@ -248,11 +652,15 @@ impl<'de, 'a> de::SeqAccess<'de> for Seq<'a, 'de> {
             return Ok(None);
         }
 
-        seed.deserialize(&mut *self.de).map(Some)
+        let index = self.index;
+        self.index += 1;
+        self.de
+            .nested(PathSegment::Index(index), |de| seed.deserialize(de))
+            .map(Some)
     }
 }
 
-impl<'de, 'a> de::MapAccess<'de> for Map<'a, 'de> {
+impl<'de, R: Read<'de>> de::MapAccess<'de> for Map<'_, R> {
     type Error = Error;
 
     fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
@@ -263,18 +671,45 @@ impl<'de, 'a> de::MapAccess<'de> for Map<'a, 'de> {
             return Ok(None);
         }
 
-        seed.deserialize(&mut *self.de).map(Some)
+        // Keys are always byte strings, so parse them here to remember the
+        // key for error paths.
+        let start = self.de.read.offset();
+        let strict = self.de.strict;
+        let mut order = None;
+        let result = self.de.parse_bytes(|key| {
+            order = self.prev_key.as_deref().map(|prev| prev.cmp(&key));
+            if strict {
+                self.prev_key = Some(key.to_vec());
+            }
+            self.key = String::from_utf8_lossy(&key).into_owned();
+            Ok(match key {
+                Reference::Borrowed(bytes) => {
+                    seed.deserialize(BorrowedBytesDeserializer::<Error>::new(bytes))
+                }
+                Reference::Copied(bytes) => seed.deserialize(BytesDeserializer::new(bytes)),
+            })
+        })?;
+
+        match order {
+            Some(Ordering::Equal) => Err(Error::DuplicateKey(self.de.position_at(start))),
+            Some(Ordering::Greater) => Err(Error::UnsortedKey(self.de.position_at(start))),
+            _ => result
+                .map(Some)
+                .map_err(|e| e.locate(|| self.de.position_at(start))),
+        }
     }
 
     fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
     where
         V: de::DeserializeSeed<'de>,
     {
-        seed.deserialize(&mut *self.de)
+        let key = std::mem::take(&mut self.key);
+        self.de
+            .nested(PathSegment::Key(key), |de| seed.deserialize(de))
     }
 }
 
-impl<'de, 'a> EnumAccess<'de> for Enum<'a, 'de> {
+impl<'de, R: Read<'de>> EnumAccess<'de> for Enum<'_, R> {
     type Error = Error;
     type Variant = Self;
 
@@ -289,7 +724,7 @@ impl<'de, 'a> EnumAccess<'de> for Enum<'a, 'de> {
 
 // `VariantAccess` is provided to the `Visitor` to give it the ability to see
 // the content of the single variant that it decided to deserialize.
-impl<'de, 'a> VariantAccess<'de> for Enum<'a, 'de> {
+impl<'de, R: Read<'de>> VariantAccess<'de> for Enum<'_, R> {
     type Error = Error;
 
     // If the `Visitor` expected this variant to be a unit variant, the input
@@ -326,32 +761,40 @@ impl<'de, 'a> VariantAccess<'de> for Enum<'a, 'de> {
     }
 }
 
-struct Seq<'a, 'de: 'a> {
-    de: &'a mut Deserializer<'de>,
+struct Seq<'a, R> {
+    de: &'a mut Deserializer<R>,
+    index: usize,
 }
 
-impl<'a, 'de> Seq<'a, 'de> {
-    fn new(de: &'a mut Deserializer<'de>) -> Self {
-        Seq { de }
+impl<'a, R> Seq<'a, R> {
+    fn new(de: &'a mut Deserializer<R>) -> Self {
+        Seq { de, index: 0 }
     }
 }
 
-struct Map<'a, 'de: 'a> {
-    de: &'a mut Deserializer<'de>,
+struct Map<'a, R> {
+    de: &'a mut Deserializer<R>,
+    key: String,
+    // Only tracked in strict mode, to check key order.
+    prev_key: Option<Vec<u8>>,
 }
 
-impl<'a, 'de> Map<'a, 'de> {
-    fn new(de: &'a mut Deserializer<'de>) -> Self {
-        Map { de }
+impl<'a, R> Map<'a, R> {
+    fn new(de: &'a mut Deserializer<R>) -> Self {
+        Map {
+            de,
+            key: String::new(),
+            prev_key: None,
+        }
     }
 }
 
-struct Enum<'a, 'de: 'a> {
-    de: &'a mut Deserializer<'de>,
+struct Enum<'a, R> {
+    de: &'a mut Deserializer<R>,
 }
 
-impl<'a, 'de> Enum<'a, 'de> {
-    fn new(de: &'a mut Deserializer<'de>) -> Self {
+impl<'a, R> Enum<'a, R> {
+    fn new(de: &'a mut Deserializer<R>) -> Self {
         Enum { de }
     }
 }
@@ -391,4 +834,198 @@ mod tests {
         let actual = from_str(j).unwrap();
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn test_error_eof() {
+        let err = from_str::<ByteBuf>("5:spam").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::UnexpectedEof(Position { offset: 6, .. })
+        ));
+    }
+
+    #[test]
+    fn test_error_invalid_integer() {
+        let err = from_str::<i64>("i5xe").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InvalidInteger(Position { offset: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_error_invalid_length() {
+        let err = from_str::<ByteBuf>("4x:spam").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InvalidLength(Position { offset: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_error_trailing_bytes() {
+        let err = from_str::<i64>("i5ei6e").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::TrailingBytes(Position { offset: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn test_error_type_mismatch() {
+        let err = from_str::<Vec<i64>>("d1:ai1ee").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::TypeMismatch {
+                expected: "list",
+                found: b'd',
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_error_path() {
+        #[derive(Debug, Deserialize)]
+        struct File {
+            #[allow(dead_code)]
+            length: i64,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Info {
+            #[allow(dead_code)]
+            files: Vec<File>,
+        }
+
+        let err = from_str::<Info>("d5:filesld6:lengthi1eed6:lengthi-eeee").unwrap_err();
+        let position = err.position().unwrap();
+        assert_eq!(position.offset, 33);
+        assert_eq!(position.path.to_string(), "files[1].length");
+
+        let err = from_str::<Info>("d5:filesld6:lengthi1eedeee").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "missing field `length` at byte 22 (files[1])"
+        );
+    }
+
+    #[test]
+    fn test_strict_integers() {
+        assert_eq!(from_bytes_strict::<i64>(b"i0e").unwrap(), 0);
+        assert_eq!(from_bytes_strict::<i64>(b"i-10e").unwrap(), -10);
+        for input in [&b"i05e"[..], b"i00e", b"i-0e", b"i-05e"] {
+            assert!(from_bytes::<i64>(input).is_ok());
+            let err = from_bytes_strict::<i64>(input).unwrap_err();
+            assert!(matches!(err, Error::InvalidInteger(_)), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_strict_lengths() {
+        assert_eq!(*from_bytes::<ByteBuf>(b"04:spam").unwrap(), b"spam");
+        let err = from_bytes_strict::<ByteBuf>(b"04:spam").unwrap_err();
+        assert!(matches!(err, Error::InvalidLength(_)));
+        assert!(from_bytes_strict::<ByteBuf>(b"0:").is_ok());
+    }
+
+    #[test]
+    fn test_strict_keys() {
+        type Dict = std::collections::BTreeMap<ByteBuf, i64>;
+
+        assert!(from_bytes_strict::<Dict>(b"d1:ai1e1:bi2ee").is_ok());
+
+        assert!(from_bytes::<Dict>(b"d1:bi1e1:ai2ee").is_ok());
+        let err = from_bytes_strict::<Dict>(b"d1:bi1e1:ai2ee").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::UnsortedKey(Position { offset: 7, .. })
+        ));
+
+        assert!(from_bytes::<Dict>(b"d1:ai1e1:ai2ee").is_ok());
+        let err = from_bytes_strict::<Dict>(b"d1:ai1e1:ai2ee").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::DuplicateKey(Position { offset: 7, .. })
+        ));
+    }
+
+    #[test]
+    fn test_borrowed() {
+        #[derive(Deserialize)]
+        struct Info<'a> {
+            name: &'a str,
+            #[serde(borrow)]
+            pieces: &'a [u8],
+        }
+
+        let input = b"d4:name4:spam6:pieces4:\x00\x01\x02\x03e";
+        let info: Info = from_bytes(input).unwrap();
+        assert_eq!(info.name, "spam");
+        assert_eq!(info.pieces, [0, 1, 2, 3]);
+        assert!(input.as_ptr_range().contains(&info.name.as_ptr()));
+        assert!(input.as_ptr_range().contains(&info.pieces.as_ptr()));
+    }
+
+    #[test]
+    fn test_non_utf8_str() {
+        let err = from_bytes::<&str>(b"2:\xff\xfe").unwrap_err();
+        assert!(matches!(err, Error::Custom { .. }));
+        assert_eq!(from_bytes::<&[u8]>(b"2:\xff\xfe").unwrap(), [0xff, 0xfe]);
+    }
+
+    #[test]
+    fn test_from_reader() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Info {
+            length: i64,
+            name: String,
+            pieces: ByteBuf,
+        }
+
+        let input: &[u8] = b"d6:lengthi5e4:name4:spam6:pieces2:\x00\xffe";
+        let info: Info = from_reader(input).unwrap();
+        assert_eq!(info, from_bytes(input).unwrap());
+
+        let err = from_reader::<_, Info>(&input[..20]).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::UnexpectedEof(Position { offset: 20, .. })
+        ));
+    }
+
+    #[test]
+    fn test_reader_limits() {
+        let limits = Limits {
+            max_depth: 2,
+            max_string_length: 4,
+            ..Limits::default()
+        };
+
+        let mut de = Deserializer::from_reader(&b"lli1eee"[..]).with_limits(limits);
+        assert!(Vec::<Vec<i64>>::deserialize(&mut de).is_ok());
+
+        let mut de = Deserializer::from_reader(&b"llli1eeee"[..]).with_limits(limits);
+        let err = Vec::<Vec<Vec<i64>>>::deserialize(&mut de).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::DepthLimitExceeded(Position { offset: 2, .. })
+        ));
+
+        let mut de = Deserializer::from_reader(&b"5:hello"[..]).with_limits(limits);
+        let err = ByteBuf::deserialize(&mut de).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::StringTooLong(Position { offset: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_reader_leaves_rest() {
+        let mut de = Deserializer::from_reader(&b"i1ei2e"[..]);
+        assert_eq!(i64::deserialize(&mut de).unwrap(), 1);
+        assert_eq!(de.byte_offset(), 3);
+        assert_eq!(i64::deserialize(&mut de).unwrap(), 2);
+        assert!(de.end().is_ok());
+    }
 }
@@ -1,13 +1,144 @@
 use std;
 use std::fmt::{self, Display};
+use std::io;
+use std::str::FromStr;
 
 use serde::{de, ser};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// One step from the root of a bencode document towards a nested value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// The chain of dictionary keys and list indices leading to a value, printed
+/// as `info.files[0].length`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Path(pub Vec<PathSegment>);
+
+impl Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("<root>");
+        }
+        for (i, segment) in self.0.iter().enumerate() {
+            match segment {
+                PathSegment::Key(key) if i == 0 => write!(f, "{key}")?,
+                PathSegment::Key(key) => write!(f, ".{key}")?,
+                PathSegment::Index(index) => write!(f, "[{index}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses the form `Display` prints, e.g. `info.files[0].length`. Keys run
+/// up to the next `.` or `[`.
+impl FromStr for Path {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Message(format!("invalid path {s:?}"));
+        let mut segments = Vec::new();
+        let mut rest = s;
+        while !rest.is_empty() {
+            if let Some(index) = rest.strip_prefix('[') {
+                let (index, tail) = index.split_once(']').ok_or_else(invalid)?;
+                segments.push(PathSegment::Index(index.parse().map_err(|_| invalid())?));
+                rest = tail;
+            } else {
+                let key = if segments.is_empty() {
+                    rest
+                } else {
+                    rest.strip_prefix('.').ok_or_else(invalid)?
+                };
+                let end = key.find(['.', '[']).unwrap_or(key.len());
+                if end == 0 {
+                    return Err(invalid());
+                }
+                segments.push(PathSegment::Key(key[..end].to_string()));
+                rest = &key[end..];
+            }
+        }
+        Ok(Path(segments))
+    }
+}
+
+/// Where in the input an error was detected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Position {
+    pub offset: usize,
+    pub path: Path,
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "byte {} ({})", self.offset, self.path)
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
+    /// A message produced by serde without any location attached, e.g. by the
+    /// serializer.
     Message(String),
+    /// A message produced by a visitor while deserializing the value at
+    /// `position`, e.g. a missing struct field.
+    Custom {
+        message: String,
+        position: Position,
+    },
+    UnexpectedEof(Position),
+    InvalidInteger(Position),
+    IntegerOutOfRange(Position),
+    InvalidLength(Position),
+    TrailingBytes(Position),
+    TypeMismatch {
+        expected: &'static str,
+        found: u8,
+        position: Position,
+    },
+    UnsortedKey(Position),
+    DuplicateKey(Position),
+    DepthLimitExceeded(Position),
+    StringTooLong(Position),
+    /// A value read as a whole is longer than [`Limits::max_value_length`](super::Limits::max_value_length).
+    ValueTooLong(Position),
+    Io(io::Error),
+}
+
+impl Error {
+    pub fn position(&self) -> Option<&Position> {
+        match self {
+            Error::Message(_) | Error::Io(_) => None,
+            Error::Custom { position, .. } | Error::TypeMismatch { position, .. } => Some(position),
+            Error::UnexpectedEof(position)
+            | Error::InvalidInteger(position)
+            | Error::IntegerOutOfRange(position)
+            | Error::InvalidLength(position)
+            | Error::TrailingBytes(position)
+            | Error::UnsortedKey(position)
+            | Error::DuplicateKey(position)
+            | Error::DepthLimitExceeded(position)
+            | Error::StringTooLong(position)
+            | Error::ValueTooLong(position) => Some(position),
+        }
+    }
+
+    // Attach a position to an error raised by a visitor, leaving errors that
+    // already know where they happened untouched.
+    pub(crate) fn locate(self, position: impl FnOnce() -> Position) -> Self {
+        match self {
+            Error::Message(message) => Error::Custom {
+                message,
+                position: position(),
+            },
+            located => located,
+        }
+    }
 }
 
 impl ser::Error for Error {
@@ -26,8 +157,50 @@ impl Display for Error {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::Message(msg) => formatter.write_str(msg),
+            Error::Custom { message, position } => write!(formatter, "{message} at {position}"),
+            Error::UnexpectedEof(position) => {
+                write!(formatter, "unexpected end of input at {position}")
+            }
+            Error::InvalidInteger(position) => write!(formatter, "invalid integer at {position}"),
+            Error::IntegerOutOfRange(position) => {
+                write!(formatter, "integer out of range at {position}")
+            }
+            Error::InvalidLength(position) => {
+                write!(formatter, "invalid string length at {position}")
+            }
+            Error::TrailingBytes(position) => write!(formatter, "trailing bytes at {position}"),
+            Error::TypeMismatch {
+                expected,
+                found,
+                position,
+            } => write!(
+                formatter,
+                "expected {expected}, found {:?} at {position}",
+                char::from(*found)
+            ),
+            Error::UnsortedKey(position) => {
+                write!(formatter, "dictionary key out of order at {position}")
+            }
+            Error::DuplicateKey(position) => {
+                write!(formatter, "duplicate dictionary key at {position}")
+            }
+            Error::DepthLimitExceeded(position) => {
+                write!(formatter, "nesting too deep at {position}")
+            }
+            Error::StringTooLong(position) => {
+                write!(formatter, "byte string too long at {position}")
+            }
+            Error::ValueTooLong(position) => write!(formatter, "value too long at {position}"),
+            Error::Io(e) => write!(formatter, "i/o error: {e}"),
         }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
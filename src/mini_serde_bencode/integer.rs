@@ -0,0 +1,157 @@
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::fmt;
+
+use super::error::{Error, Result};
+
+// The newtype struct name `BigInteger` serializes as, so the serializer
+// writes its digits as an integer.
+pub(crate) const TOKEN: &str = "$mini_serde_bencode::private::BigInteger";
+
+/// An integer of any size, kept as its decimal digits.
+///
+/// Bencode puts no bound on integers; values that fit in 128 bits are handed
+/// to visitors as native integers, anything larger as a newtype struct
+/// wrapping the digits, which this type unwraps.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigInteger(String);
+
+impl BigInteger {
+    /// Checks that `digits` is a decimal integer with an optional `-` sign.
+    pub fn new(digits: impl Into<String>) -> Result<Self> {
+        let digits = digits.into();
+        let magnitude = digits.strip_prefix('-').unwrap_or(&digits);
+        if magnitude.is_empty() || !magnitude.bytes().all(|by| by.is_ascii_digit()) {
+            return Err(Error::Message(format!("invalid integer {digits:?}")));
+        }
+        Ok(Self(digits))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for BigInteger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+macro_rules! impl_from {
+    ($($ty:ty)*) => {
+        $(
+            impl From<$ty> for BigInteger {
+                fn from(int: $ty) -> Self {
+                    Self(int.to_string())
+                }
+            }
+        )*
+    };
+}
+
+impl_from!(i64 u64 i128 u128);
+
+impl<'de> Deserialize<'de> for BigInteger {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct BigIntegerVisitor;
+
+        impl<'de> Visitor<'de> for BigIntegerVisitor {
+            type Value = BigInteger;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("an integer")
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> std::result::Result<BigInteger, E> {
+                Ok(v.into())
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> std::result::Result<BigInteger, E> {
+                Ok(v.into())
+            }
+
+            fn visit_i128<E: de::Error>(self, v: i128) -> std::result::Result<BigInteger, E> {
+                Ok(v.into())
+            }
+
+            fn visit_u128<E: de::Error>(self, v: u128) -> std::result::Result<BigInteger, E> {
+                Ok(v.into())
+            }
+
+            fn visit_newtype_struct<D>(
+                self,
+                deserializer: D,
+            ) -> std::result::Result<BigInteger, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                let digits = String::deserialize(deserializer)?;
+                BigInteger::new(digits).map_err(de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(BigIntegerVisitor)
+    }
+}
+
+impl Serialize for BigInteger {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_newtype_struct(TOKEN, &self.0)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mini_serde_bencode::{from_str, to_string, Error};
+
+    #[test]
+    fn test_native_integers() {
+        assert_eq!(from_str::<u64>("i18446744073709551615e").unwrap(), u64::MAX);
+        assert_eq!(from_str::<i64>("i-9223372036854775808e").unwrap(), i64::MIN);
+        assert_eq!(
+            from_str::<i128>("i-170141183460469231731687303715884105728e").unwrap(),
+            i128::MIN
+        );
+        assert_eq!(
+            from_str::<u128>("i340282366920938463463374607431768211455e").unwrap(),
+            u128::MAX
+        );
+        assert_eq!(to_string(&u64::MAX).unwrap(), "i18446744073709551615e");
+    }
+
+    #[test]
+    fn test_overflow() {
+        assert!(from_str::<u32>("i4294967296e").is_err());
+        assert!(from_str::<i64>("i9223372036854775808e").is_err());
+        assert!(from_str::<u64>("i-1e").is_err());
+
+        let err = from_str::<u128>("i340282366920938463463374607431768211456e").unwrap_err();
+        assert!(matches!(err, Error::IntegerOutOfRange(_)));
+    }
+
+    #[test]
+    fn test_big_integer() {
+        let input = "i-1234567890123456789012345678901234567890e";
+        let int = from_str::<BigInteger>(input).unwrap();
+        assert_eq!(int.as_str(), "-1234567890123456789012345678901234567890");
+        assert_eq!(to_string(&int).unwrap(), input);
+
+        let int = from_str::<BigInteger>("i42e").unwrap();
+        assert_eq!(int, BigInteger::from(42i64));
+
+        assert!(BigInteger::new("12a").is_err());
+        assert!(BigInteger::new("-").is_err());
+    }
+}
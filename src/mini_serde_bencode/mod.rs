@@ -1,7 +1,196 @@
+//! A serde data format for bencode.
+//!
+//! Bencode only has integers, byte strings, lists and dictionaries, so the
+//! rest of the serde data model is mapped onto them:
+//!
+//! | Rust                               | Bencode                               |
+//! |------------------------------------|---------------------------------------|
+//! | integers of any size               | `i<n>e`                               |
+//! | `bool`                             | `i0e` / `i1e`                         |
+//! | `str`, `String`, `char`, bytes     | `<len>:<bytes>`                       |
+//! | `()` and unit structs              | the empty string `0:`                 |
+//! | `Some(v)`                          | `v`                                   |
+//! | `None`                             | the dictionary key is left out        |
+//! | sequences, tuples, tuple structs   | `l...e`                               |
+//! | maps, structs                      | `d...e`, keys sorted as raw bytes     |
+//! | unit variants                      | the variant name as a string          |
+//! | newtype/tuple/struct variants      | `d<name><value>e`                     |
+//! | `#[serde(flatten)]`                | the fields merged into the dictionary |
+//!
+//! Floats have no bencode form and fail to serialize. `None` anywhere other
+//! than a dictionary value fails too, as there is nothing to write. Flattened
+//! fields are buffered by serde and lose their hints, so a `bool` inside a
+//! flattened struct cannot be read back.
+
+mod async_read;
 mod de;
 mod error;
+mod integer;
+mod raw;
+mod read;
 mod ser;
 
-pub use de::{from_bytes, from_str, Deserializer};
-pub use error::{Error, Result};
+pub use async_read::{from_async_reader, read_value};
+pub use de::{from_bytes, from_bytes_strict, from_reader, from_str, Deserializer, Limits};
+pub use error::{Error, Path, PathSegment, Position, Result};
+pub use integer::BigInteger;
+pub use raw::{RawValue, WithRaw};
+pub use read::{IoRead, Read, Reference, SliceRead};
 pub use ser::{to_bytes, to_string, Serializer};
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+    use std::collections::BTreeMap;
+    use std::fmt::Debug;
+
+    fn round_trip<T>(value: &T, expected: &str)
+    where
+        T: Serialize + DeserializeOwned + PartialEq + Debug,
+    {
+        assert_eq!(to_string(value).unwrap(), expected);
+        assert_eq!(&from_str::<T>(expected).unwrap(), value);
+    }
+
+    #[test]
+    fn test_bool() {
+        round_trip(&true, "i1e");
+        round_trip(&false, "i0e");
+        assert!(from_str::<bool>("i2e").is_err());
+    }
+
+    #[test]
+    fn test_option() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Info {
+            name: String,
+            private: Option<bool>,
+        }
+
+        let info = Info {
+            name: "spam".to_string(),
+            private: Some(true),
+        };
+        round_trip(&info, "d4:name4:spam7:privatei1ee");
+
+        let info = Info {
+            name: "spam".to_string(),
+            private: None,
+        };
+        round_trip(&info, "d4:name4:spame");
+
+        assert!(to_bytes(&vec![Some(1), None]).is_err());
+        assert!(to_bytes(&None::<i64>).is_err());
+    }
+
+    #[test]
+    fn test_default() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        #[serde(default)]
+        struct Response {
+            interval: u64,
+            complete: u64,
+        }
+
+        impl Default for Response {
+            fn default() -> Self {
+                Self {
+                    interval: 1800,
+                    complete: 0,
+                }
+            }
+        }
+
+        let response = from_str::<Response>("d8:completei5ee").unwrap();
+        assert_eq!(
+            response,
+            Response {
+                interval: 1800,
+                complete: 5
+            }
+        );
+    }
+
+    #[test]
+    fn test_unit() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Marker;
+
+        round_trip(&(), "0:");
+        round_trip(&Marker, "0:");
+        assert!(from_str::<()>("1:a").is_err());
+    }
+
+    #[test]
+    fn test_enum() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        #[serde(rename_all = "lowercase")]
+        enum Event {
+            Started,
+            Stopped,
+            Completed,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Message {
+            Request { piece: u32 },
+            Reject(u32),
+            Pair(u32, u32),
+        }
+
+        round_trip(&Event::Started, "7:started");
+        round_trip(
+            &vec![Event::Stopped, Event::Completed],
+            "l7:stopped9:completede",
+        );
+        round_trip(&Message::Request { piece: 1 }, "d7:Requestd5:piecei1eee");
+        round_trip(&Message::Reject(2), "d6:Rejecti2ee");
+        round_trip(&Message::Pair(1, 2), "d4:Pairli1ei2eee");
+        assert!(from_str::<Event>("6:paused").is_err());
+    }
+
+    #[test]
+    fn test_flatten() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Torrent {
+            announce: String,
+            #[serde(flatten)]
+            extra: BTreeMap<String, serde_bytes::ByteBuf>,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Common {
+            comment: Option<String>,
+            #[serde(rename = "creation date")]
+            creation_date: i64,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Metainfo {
+            announce: String,
+            #[serde(flatten)]
+            common: Common,
+        }
+
+        let torrent = Torrent {
+            announce: "http://a/".to_string(),
+            extra: BTreeMap::from([(
+                "comment".to_string(),
+                serde_bytes::ByteBuf::from(b"hi".to_vec()),
+            )]),
+        };
+        round_trip(&torrent, "d8:announce9:http://a/7:comment2:hie");
+
+        let metainfo = Metainfo {
+            announce: "http://a/".to_string(),
+            common: Common {
+                comment: None,
+                creation_date: 5,
+            },
+        };
+        round_trip(&metainfo, "d8:announce9:http://a/13:creation datei5ee");
+    }
+}
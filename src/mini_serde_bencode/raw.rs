@@ -0,0 +1,193 @@
+use serde::{
+    de::{self, DeserializeOwned, Visitor},
+    ser, Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{fmt, ops::Deref};
+
+use super::{de::from_bytes, error::Result, ser::to_bytes};
+
+// The newtype struct name the deserializer and serializer look for to pass a
+// value through as its exact bytes.
+pub(crate) const TOKEN: &str = "$mini_serde_bencode::private::RawValue";
+
+/// A bencode value kept as the exact bytes it was parsed from, written back
+/// out unchanged when serialized.
+///
+/// Only this crate's [`Deserializer`](super::Deserializer) and
+/// [`Serializer`](super::Serializer) know how to handle it.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct RawValue(Vec<u8>);
+
+impl RawValue {
+    /// Checks that `bytes` hold exactly one bencode value.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        from_bytes::<de::IgnoredAny>(&bytes)?;
+        Ok(Self(bytes))
+    }
+
+    pub fn get(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl fmt::Debug for RawValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RawValue")
+            .field(&String::from_utf8_lossy(&self.0))
+            .finish()
+    }
+}
+
+impl<'de> Deserialize<'de> for RawValue {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct RawVisitor;
+
+        impl Visitor<'_> for RawVisitor {
+            type Value = RawValue;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("any bencode value")
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> std::result::Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Ok(RawValue(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E>(self, v: Vec<u8>) -> std::result::Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                Ok(RawValue(v))
+            }
+        }
+
+        deserializer.deserialize_newtype_struct(TOKEN, RawVisitor)
+    }
+}
+
+impl Serialize for RawValue {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_newtype_struct(TOKEN, serde_bytes::Bytes::new(&self.0))
+    }
+}
+
+/// A deserialized value together with the bytes it was parsed from.
+///
+/// Dereferences to the value. Serializing writes the original bytes, so
+/// fields the type does not model survive a round trip.
+#[derive(Clone)]
+pub struct WithRaw<T> {
+    value: T,
+    raw: RawValue,
+}
+
+impl<T> WithRaw<T> {
+    pub fn raw(&self) -> &RawValue {
+        &self.raw
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T: Serialize> WithRaw<T> {
+    /// Wraps a value built in code, encoding it to get its bytes.
+    pub fn new(value: T) -> Result<Self> {
+        let raw = RawValue(to_bytes(&value)?);
+        Ok(Self { value, raw })
+    }
+}
+
+impl<T> Deref for WithRaw<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for WithRaw<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for WithRaw<T> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = RawValue::deserialize(deserializer)?;
+        let value = from_bytes(raw.get()).map_err(de::Error::custom)?;
+        Ok(Self { value, raw })
+    }
+}
+
+impl<T> Serialize for WithRaw<T> {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        self.raw.serialize(serializer)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mini_serde_bencode::{from_reader, to_string};
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Info {
+        name: String,
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Torrent {
+        info: WithRaw<Info>,
+    }
+
+    #[test]
+    fn test_raw_value() {
+        let input = "d4:infod4:name4:spam7:privatei1eee";
+        let torrent = from_bytes::<Torrent>(input.as_bytes()).unwrap();
+        assert_eq!(torrent.info.name, "spam");
+        assert_eq!(torrent.info.raw().get(), b"d4:name4:spam7:privatei1ee");
+        assert_eq!(to_string(&torrent).unwrap(), input);
+
+        let torrent = from_reader::<_, Torrent>(input.as_bytes()).unwrap();
+        assert_eq!(torrent.info.raw().get(), b"d4:name4:spam7:privatei1ee");
+    }
+
+    #[test]
+    fn test_raw_value_from_bytes() {
+        assert!(RawValue::from_bytes(b"li1ee".to_vec()).is_ok());
+        assert!(RawValue::from_bytes(b"li1e".to_vec()).is_err());
+        assert!(RawValue::from_bytes(b"i1ei2e".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_with_raw_new() {
+        let info = WithRaw::new(Info {
+            name: "spam".to_string(),
+        })
+        .unwrap();
+        assert_eq!(info.raw().get(), b"d4:name4:spame");
+    }
+}
@@ -0,0 +1,194 @@
+use std::io;
+
+/// A byte string handed out by a [`Read`] source, either borrowed from the
+/// input itself or copied into the deserializer's scratch buffer.
+pub enum Reference<'b, 'c> {
+    Borrowed(&'b [u8]),
+    Copied(&'c [u8]),
+}
+
+impl std::ops::Deref for Reference<'_, '_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Reference::Borrowed(bytes) => bytes,
+            Reference::Copied(bytes) => bytes,
+        }
+    }
+}
+
+/// The input the [`Deserializer`](super::Deserializer) pulls bytes from.
+pub trait Read<'de> {
+    /// Look at the next byte without consuming it, or `None` at end of input.
+    fn peek(&mut self) -> io::Result<Option<u8>>;
+
+    /// Consume the byte returned by the last call to `peek`.
+    fn discard(&mut self);
+
+    /// The number of bytes consumed so far.
+    fn offset(&self) -> usize;
+
+    /// Consume the next `len` bytes, or return `None` if the input ends first.
+    fn read_bytes<'s>(
+        &'s mut self,
+        len: usize,
+        scratch: &'s mut Vec<u8>,
+    ) -> io::Result<Option<Reference<'de, 's>>>;
+
+    /// Start recording the bytes consumed from here on.
+    fn begin_raw(&mut self);
+
+    /// Stop recording and return every byte consumed since `begin_raw`.
+    fn end_raw(&mut self) -> Reference<'de, '_>;
+}
+
+/// Reads from an in-memory slice, borrowing byte strings from it.
+pub struct SliceRead<'de> {
+    slice: &'de [u8],
+    offset: usize,
+    raw_start: usize,
+}
+
+impl<'de> SliceRead<'de> {
+    pub fn new(slice: &'de [u8]) -> Self {
+        Self {
+            slice,
+            offset: 0,
+            raw_start: 0,
+        }
+    }
+}
+
+impl<'de> Read<'de> for SliceRead<'de> {
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.slice.get(self.offset).copied())
+    }
+
+    fn discard(&mut self) {
+        self.offset += 1;
+    }
+
+    fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn read_bytes<'s>(
+        &'s mut self,
+        len: usize,
+        _scratch: &'s mut Vec<u8>,
+    ) -> io::Result<Option<Reference<'de, 's>>> {
+        let Some(end) = self
+            .offset
+            .checked_add(len)
+            .filter(|end| *end <= self.slice.len())
+        else {
+            self.offset = self.slice.len();
+            return Ok(None);
+        };
+        let bytes = &self.slice[self.offset..end];
+        self.offset = end;
+        Ok(Some(Reference::Borrowed(bytes)))
+    }
+
+    fn begin_raw(&mut self) {
+        self.raw_start = self.offset;
+    }
+
+    fn end_raw(&mut self) -> Reference<'de, '_> {
+        Reference::Borrowed(&self.slice[self.raw_start..self.offset])
+    }
+}
+
+/// Reads from any [`io::Read`] one byte at a time, copying byte strings into
+/// scratch space. Wrap unbuffered readers such as files and sockets in an
+/// [`io::BufReader`].
+pub struct IoRead<R> {
+    reader: R,
+    peeked: Option<u8>,
+    offset: usize,
+    raw: Option<Vec<u8>>,
+    raw_done: Vec<u8>,
+}
+
+impl<R: io::Read> IoRead<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            peeked: None,
+            offset: 0,
+            raw: None,
+            raw_done: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<'de, R: io::Read> Read<'de> for IoRead<R> {
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        if self.peeked.is_none() {
+            let mut buf = [0];
+            loop {
+                match self.reader.read(&mut buf) {
+                    Ok(0) => return Ok(None),
+                    Ok(_) => break,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            self.peeked = Some(buf[0]);
+        }
+        Ok(self.peeked)
+    }
+
+    fn discard(&mut self) {
+        if let Some(by) = self.peeked.take() {
+            self.offset += 1;
+            if let Some(raw) = &mut self.raw {
+                raw.push(by);
+            }
+        }
+    }
+
+    fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn read_bytes<'s>(
+        &'s mut self,
+        len: usize,
+        scratch: &'s mut Vec<u8>,
+    ) -> io::Result<Option<Reference<'de, 's>>> {
+        scratch.clear();
+        if len > 0 {
+            if let Some(by) = self.peeked.take() {
+                scratch.push(by);
+            }
+        }
+        // Let the buffer grow with the data actually received rather than
+        // trusting the announced length up front.
+        let rest = (len - scratch.len()) as u64;
+        let mut body = io::Read::take(&mut self.reader, rest);
+        io::Read::read_to_end(&mut body, scratch)?;
+        self.offset += scratch.len();
+        if let Some(raw) = &mut self.raw {
+            raw.extend_from_slice(scratch);
+        }
+        if scratch.len() < len {
+            return Ok(None);
+        }
+        Ok(Some(Reference::Copied(scratch)))
+    }
+
+    fn begin_raw(&mut self) {
+        self.raw = Some(Vec::new());
+    }
+
+    fn end_raw(&mut self) -> Reference<'de, '_> {
+        self.raw_done = self.raw.take().unwrap_or_default();
+        Reference::Copied(&self.raw_done)
+    }
+}
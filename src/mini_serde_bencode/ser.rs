@@ -1,6 +1,12 @@
-use serde::{ser, Serialize};
+use serde::{
+    ser::{self, Impossible},
+    Serialize,
+};
 
-use super::error::{Error, Result};
+use super::{
+    error::{Error, Result},
+    integer, raw,
+};
 
 pub struct Serializer {
     output: Vec<u8>,
@@ -24,17 +30,35 @@ impl Serializer {
     fn push<T: AsRef<[u8]>>(&mut self, token: T) {
         self.output.extend_from_slice(token.as_ref());
     }
+
+    // Serialize a value that has to be present in the output. Only `None`
+    // produces no bytes, and bencode can only express it by leaving a
+    // dictionary key out.
+    fn serialize_present<T>(&mut self, value: &T) -> Result<()>
+    where
+        T: Serialize,
+        T: ?Sized,
+    {
+        let len = self.output.len();
+        value.serialize(&mut *self)?;
+        if self.output.len() == len {
+            return Err(Error::Message(
+                "None can only be serialized as a dictionary value".to_string(),
+            ));
+        }
+        Ok(())
+    }
 }
 
 pub fn to_bytes<T: ser::Serialize>(b: &T) -> Result<Vec<u8>> {
     let mut ser = Serializer::new();
-    b.serialize(&mut ser)?;
+    ser.serialize_present(b)?;
     Ok(ser.into_vec())
 }
 
 pub fn to_string<T: ser::Serialize>(b: &T) -> Result<String> {
     let mut ser = Serializer::new();
-    b.serialize(&mut ser)?;
+    ser.serialize_present(b)?;
     Ok(String::from_utf8_lossy(ser.as_ref()).to_string())
 }
 
@@ -57,14 +81,14 @@ impl<'a> ser::Serializer for &'a mut Serializer {
 
     type SerializeTupleVariant = Self;
 
-    type SerializeMap = Self;
+    type SerializeMap = SerializeDict<'a>;
 
-    type SerializeStruct = Self;
+    type SerializeStruct = SerializeDict<'a>;
 
-    type SerializeStructVariant = Self;
+    type SerializeStructVariant = SerializeDict<'a>;
 
-    fn serialize_bool(self, _v: bool) -> Result<()> {
-        Err(Error::Message("Cannot serialize bool".to_string()))
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.serialize_u64(u64::from(v))
     }
 
     fn serialize_i8(self, v: i8) -> Result<()> {
@@ -86,6 +110,13 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         Ok(())
     }
 
+    fn serialize_i128(self, v: i128) -> Result<()> {
+        self.push("i");
+        self.push(v.to_string());
+        self.push("e");
+        Ok(())
+    }
+
     fn serialize_u8(self, v: u8) -> Result<()> {
         self.serialize_u64(u64::from(v))
     }
@@ -105,6 +136,13 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         Ok(())
     }
 
+    fn serialize_u128(self, v: u128) -> Result<()> {
+        self.push("i");
+        self.push(v.to_string());
+        self.push("e");
+        Ok(())
+    }
+
     fn serialize_f32(self, _v: f32) -> Result<()> {
         Err(Error::Message("Cannot serialize f32".to_string()))
     }
@@ -129,6 +167,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         Ok(())
     }
 
+    // Writes nothing; the enclosing dictionary drops the key.
     fn serialize_none(self) -> Result<()> {
         Ok(())
     }
@@ -142,7 +181,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     }
 
     fn serialize_unit(self) -> Result<()> {
-        Ok(())
+        self.serialize_bytes(&[])
     }
 
     fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
@@ -158,11 +197,24 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         self.serialize_str(variant)
     }
 
-    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<()>
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<()>
     where
         T: Serialize,
         T: ?Sized,
     {
+        if name == raw::TOKEN {
+            // A `RawValue` is already bencode, so write it out verbatim.
+            let raw = value.serialize(BytesSerializer)?;
+            self.push(raw);
+            return Ok(());
+        }
+        if name == integer::TOKEN {
+            let digits = value.serialize(BytesSerializer)?;
+            self.push("i");
+            self.push(digits);
+            self.push("e");
+            return Ok(());
+        }
         value.serialize(self)
     }
 
@@ -179,7 +231,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     {
         self.push("d");
         self.serialize_bytes(variant.as_bytes())?;
-        value.serialize(&mut *self)?;
+        self.serialize_present(value)?;
         self.push("e");
         Ok(())
     }
@@ -216,7 +268,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
 
     fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
         self.push("d");
-        Ok(self)
+        Ok(SerializeDict::new(self, "e"))
     }
 
     fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeStruct> {
@@ -233,11 +285,11 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         self.push("d");
         self.serialize_bytes(variant.as_bytes())?;
         self.push("d");
-        Ok(self)
+        Ok(SerializeDict::new(self, "ee"))
     }
 }
 
-impl<'a> ser::SerializeSeq for &'a mut Serializer {
+impl ser::SerializeSeq for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -245,7 +297,7 @@ impl<'a> ser::SerializeSeq for &'a mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        value.serialize(&mut **self)
+        self.serialize_present(value)
     }
 
     fn end(self) -> Result<()> {
@@ -254,7 +306,7 @@ impl<'a> ser::SerializeSeq for &'a mut Serializer {
     }
 }
 
-impl<'a> ser::SerializeTuple for &'a mut Serializer {
+impl ser::SerializeTuple for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -262,7 +314,7 @@ impl<'a> ser::SerializeTuple for &'a mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        value.serialize(&mut **self)
+        self.serialize_present(value)
     }
 
     fn end(self) -> Result<()> {
@@ -271,7 +323,7 @@ impl<'a> ser::SerializeTuple for &'a mut Serializer {
     }
 }
 
-impl<'a> ser::SerializeTupleStruct for &'a mut Serializer {
+impl ser::SerializeTupleStruct for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -279,7 +331,7 @@ impl<'a> ser::SerializeTupleStruct for &'a mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        value.serialize(&mut **self)
+        self.serialize_present(value)
     }
 
     fn end(self) -> Result<()> {
@@ -288,7 +340,7 @@ impl<'a> ser::SerializeTupleStruct for &'a mut Serializer {
     }
 }
 
-impl<'a> ser::SerializeTupleVariant for &'a mut Serializer {
+impl ser::SerializeTupleVariant for &mut Serializer {
     type Ok = ();
     type Error = Error;
 
@@ -296,7 +348,7 @@ impl<'a> ser::SerializeTupleVariant for &'a mut Serializer {
     where
         T: ?Sized + Serialize,
     {
-        value.serialize(&mut **self)
+        self.serialize_present(value)
     }
 
     fn end(self) -> Result<()> {
@@ -305,7 +357,59 @@ impl<'a> ser::SerializeTupleVariant for &'a mut Serializer {
     }
 }
 
-impl<'a> ser::SerializeMap for &'a mut Serializer {
+/// Collects the entries of a dictionary so they can be written sorted by their
+/// raw key bytes, as canonical bencode requires.
+pub struct SerializeDict<'a> {
+    ser: &'a mut Serializer,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    key: Option<Vec<u8>>,
+    closing: &'static str,
+}
+
+impl<'a> SerializeDict<'a> {
+    fn new(ser: &'a mut Serializer, closing: &'static str) -> Self {
+        Self {
+            ser,
+            entries: Vec::new(),
+            key: None,
+            closing,
+        }
+    }
+
+    fn push_entry<T>(&mut self, key: Vec<u8>, value: &T) -> Result<()>
+    where
+        T: Serialize,
+        T: ?Sized,
+    {
+        let mut ser = Serializer::new();
+        value.serialize(&mut ser)?;
+        // An empty value is `None`, which is written by leaving the key out.
+        if !ser.output.is_empty() {
+            self.entries.push((key, ser.into_vec()));
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        let mut entries = self.entries;
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        if let Some(pair) = entries.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(Error::Message(format!(
+                "duplicate dictionary key {:?}",
+                String::from_utf8_lossy(&pair[0].0)
+            )));
+        }
+
+        for (key, value) in entries {
+            ser::Serializer::serialize_bytes(&mut *self.ser, &key)?;
+            self.ser.push(value);
+        }
+        self.ser.push(self.closing);
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for SerializeDict<'_> {
     type Ok = ();
 
     type Error = Error;
@@ -315,7 +419,8 @@ impl<'a> ser::SerializeMap for &'a mut Serializer {
         T: Serialize,
         T: ?Sized,
     {
-        key.serialize(&mut **self)
+        self.key = Some(key.serialize(BytesSerializer)?);
+        Ok(())
     }
 
     fn serialize_value<T>(&mut self, value: &T) -> Result<()>
@@ -323,16 +428,19 @@ impl<'a> ser::SerializeMap for &'a mut Serializer {
         T: Serialize,
         T: ?Sized,
     {
-        value.serialize(&mut **self)
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| Error::Message("value serialized before its key".to_string()))?;
+        self.push_entry(key, value)
     }
 
     fn end(self) -> Result<()> {
-        self.push("e");
-        Ok(())
+        self.finish()
     }
 }
 
-impl<'a> ser::SerializeStruct for &'a mut Serializer {
+impl ser::SerializeStruct for SerializeDict<'_> {
     type Ok = ();
 
     type Error = Error;
@@ -342,18 +450,15 @@ impl<'a> ser::SerializeStruct for &'a mut Serializer {
         T: Serialize,
         T: ?Sized,
     {
-        key.serialize(&mut **self)?;
-        value.serialize(&mut **self)?;
-        Ok(())
+        self.push_entry(key.as_bytes().to_vec(), value)
     }
 
     fn end(self) -> Result<()> {
-        self.push("e");
-        Ok(())
+        self.finish()
     }
 }
 
-impl<'a> ser::SerializeStructVariant for &'a mut Serializer {
+impl ser::SerializeStructVariant for SerializeDict<'_> {
     type Ok = ();
 
     type Error = Error;
@@ -363,14 +468,191 @@ impl<'a> ser::SerializeStructVariant for &'a mut Serializer {
         T: Serialize,
         T: ?Sized,
     {
-        key.serialize(&mut **self)?;
-        value.serialize(&mut **self)?;
-        Ok(())
+        self.push_entry(key.as_bytes().to_vec(), value)
     }
 
     fn end(self) -> Result<()> {
-        self.push("ee");
-        Ok(())
+        self.finish()
+    }
+}
+
+// Serializes a byte string to its raw bytes, for dictionary keys and raw
+// values. Anything else is rejected.
+struct BytesSerializer;
+
+fn not_bytes_error() -> Error {
+    Error::Message("expected a byte string, e.g. as a dictionary key".to_string())
+}
+
+impl ser::Serializer for BytesSerializer {
+    type Ok = Vec<u8>;
+
+    type Error = Error;
+
+    type SerializeSeq = Impossible<Vec<u8>, Error>;
+
+    type SerializeTuple = Impossible<Vec<u8>, Error>;
+
+    type SerializeTupleStruct = Impossible<Vec<u8>, Error>;
+
+    type SerializeTupleVariant = Impossible<Vec<u8>, Error>;
+
+    type SerializeMap = Impossible<Vec<u8>, Error>;
+
+    type SerializeStruct = Impossible<Vec<u8>, Error>;
+
+    type SerializeStructVariant = Impossible<Vec<u8>, Error>;
+
+    fn serialize_bool(self, _v: bool) -> Result<Vec<u8>> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_i8(self, _v: i8) -> Result<Vec<u8>> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_i16(self, _v: i16) -> Result<Vec<u8>> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_i32(self, _v: i32) -> Result<Vec<u8>> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_i64(self, _v: i64) -> Result<Vec<u8>> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_u8(self, _v: u8) -> Result<Vec<u8>> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_u16(self, _v: u16) -> Result<Vec<u8>> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_u32(self, _v: u32) -> Result<Vec<u8>> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_u64(self, _v: u64) -> Result<Vec<u8>> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Vec<u8>> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Vec<u8>> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_char(self, v: char) -> Result<Vec<u8>> {
+        let mut buf = [0; 4];
+        self.serialize_bytes(v.encode_utf8(&mut buf).as_bytes())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Vec<u8>> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Vec<u8>> {
+        Ok(v.to_vec())
+    }
+
+    fn serialize_none(self) -> Result<Vec<u8>> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<Vec<u8>>
+    where
+        T: Serialize,
+        T: ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Vec<u8>> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Vec<u8>> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Vec<u8>> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T>(self, _name: &'static str, value: &T) -> Result<Vec<u8>>
+    where
+        T: Serialize,
+        T: ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<Vec<u8>>
+    where
+        T: Serialize,
+        T: ?Sized,
+    {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Err(not_bytes_error())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(not_bytes_error())
     }
 }
 
@@ -423,4 +705,54 @@ mod tests {
         let expected = "d6:Structd1:ai1eee";
         assert_eq!(to_string(&s).unwrap(), expected);
     }
+
+    #[test]
+    fn test_sorted_keys() {
+        #[derive(Serialize)]
+        struct Info {
+            name: &'static str,
+            length: u32,
+            #[serde(rename = "piece length")]
+            piece_length: u32,
+        }
+
+        let info = Info {
+            name: "a",
+            length: 1,
+            piece_length: 2,
+        };
+        let expected = "d6:lengthi1e4:name1:a12:piece lengthi2ee";
+        assert_eq!(to_string(&info).unwrap(), expected);
+
+        let map = std::collections::HashMap::from([("b", 2), ("a", 1), ("c", 3)]);
+        let expected = "d1:ai1e1:bi2e1:ci3ee";
+        assert_eq!(to_string(&map).unwrap(), expected);
+
+        // Keys compare as raw bytes, so "B" sorts before "a".
+        let map = std::collections::HashMap::from([("a", 1), ("B", 2)]);
+        let expected = "d1:Bi2e1:ai1ee";
+        assert_eq!(to_string(&map).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        struct Duplicates;
+
+        impl Serialize for Duplicates {
+            fn serialize<S: ser::Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                serializer.collect_map([("a", 1), ("a", 2)])
+            }
+        }
+
+        assert!(to_bytes(&Duplicates).is_err());
+    }
+
+    #[test]
+    fn test_non_string_keys() {
+        let map = std::collections::BTreeMap::from([(1, 2)]);
+        assert!(to_bytes(&map).is_err());
+    }
 }
@@ -1,13 +1,19 @@
 use anyhow::{anyhow, Result};
-use bittorrent_starter_rust::bitmap::BitMap;
+use bittorrent_starter_rust::{bitmap::BitMap, torrent::Torrent};
 use sha1::{Digest, Sha1};
-use std::{cmp::min, fmt::Display, sync::Arc};
+use std::{
+    cmp::min,
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
 };
 
-use crate::torrent::Torrent;
+use crate::identity::Identity;
 
 #[derive(Debug)]
 pub enum PeerMessage {
@@ -21,6 +27,137 @@ pub enum PeerMessage {
     Request(u32, u32, u32),
     Piece(u32, u32, Vec<u8>),
     Cancel(u32, u32, u32),
+    /// A BEP 10 extension message: the extended message id and its payload.
+    Extended(u8, Vec<u8>),
+}
+
+// Room for the bitfield of a torrent with millions of pieces, which is far
+// more than a block or a metadata piece needs, while keeping a peer from
+// making us allocate gigabytes.
+const MAX_MESSAGE_LEN: usize = 2 * 1024 * 1024;
+
+/// Splits a message payload into `N` big-endian integers and the bytes after
+/// them.
+fn split_u32s<const N: usize>(payload: &[u8]) -> Result<([u32; N], &[u8])> {
+    if payload.len() < 4 * N {
+        return Err(anyhow!(
+            "Message payload of {} bytes is too short",
+            payload.len()
+        ));
+    }
+    let mut values = [0; N];
+    for (value, chunk) in values.iter_mut().zip(payload.chunks_exact(4)) {
+        *value = u32::from_be_bytes(chunk.try_into().expect("chunks are 4 bytes"));
+    }
+    Ok((values, &payload[4 * N..]))
+}
+
+impl PeerMessage {
+    /// Writes the message with its length prefix.
+    pub async fn write_to<W>(self, socket: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = Vec::new();
+        match self {
+            PeerMessage::KeepAlive => {}
+            PeerMessage::Choke => buf.push(0),
+            PeerMessage::Unchoke => buf.push(1),
+            PeerMessage::Interested => buf.push(2),
+            PeerMessage::NotInterested => buf.push(3),
+            PeerMessage::Have(piece) => {
+                buf.push(4);
+                buf.extend(&piece.to_be_bytes());
+            }
+            PeerMessage::Bitfield(bitfield) => {
+                buf.push(5);
+                buf.extend(&bitfield);
+            }
+            PeerMessage::Request(index, begin, length) => {
+                buf.push(6);
+                buf.extend(&index.to_be_bytes());
+                buf.extend(&begin.to_be_bytes());
+                buf.extend(&length.to_be_bytes());
+            }
+            PeerMessage::Piece(index, begin, block) => {
+                buf.push(7);
+                buf.extend(&index.to_be_bytes());
+                buf.extend(&begin.to_be_bytes());
+                buf.extend(&block);
+            }
+            PeerMessage::Cancel(index, begin, length) => {
+                buf.push(8);
+                buf.extend(&index.to_be_bytes());
+                buf.extend(&begin.to_be_bytes());
+                buf.extend(&length.to_be_bytes());
+            }
+            PeerMessage::Extended(id, payload) => {
+                buf.push(20);
+                buf.push(id);
+                buf.extend(&payload);
+            }
+        }
+
+        let len = u32::try_from(buf.len())?;
+        let len_buf = len.to_be_bytes();
+        let mut message_buf = Vec::new();
+        message_buf.extend(&len_buf);
+        message_buf.extend(&buf);
+
+        socket.write_all(&message_buf).await?;
+        socket.flush().await?;
+        Ok(())
+    }
+
+    /// Reads one length-prefixed message.
+    pub async fn read_from<R>(socket: &mut R) -> Result<PeerMessage>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut len_buf = [0; 4];
+
+        socket.read_exact(&mut len_buf).await?;
+
+        let len = u32::from_be_bytes(len_buf) as usize;
+        if len == 0 {
+            return Ok(PeerMessage::KeepAlive);
+        }
+        if len > MAX_MESSAGE_LEN {
+            return Err(anyhow!("Message of {len} bytes is too long"));
+        }
+        let mut id_buf = [0; 1];
+        socket.read_exact(&mut id_buf).await?;
+        let id = id_buf[0];
+        let mut payload = vec![0; len - 1];
+        socket.read_exact(&mut payload).await?;
+        match id {
+            0 => Ok(PeerMessage::Choke),
+            1 => Ok(PeerMessage::Unchoke),
+            2 => Ok(PeerMessage::Interested),
+            3 => Ok(PeerMessage::NotInterested),
+            4 => {
+                let ([piece], _) = split_u32s(&payload)?;
+                Ok(PeerMessage::Have(piece))
+            }
+            5 => Ok(PeerMessage::Bitfield(payload)),
+            6 => {
+                let ([index, begin, length], _) = split_u32s(&payload)?;
+                Ok(PeerMessage::Request(index, begin, length))
+            }
+            7 => {
+                let ([index, begin], block) = split_u32s(&payload)?;
+                Ok(PeerMessage::Piece(index, begin, block.to_vec()))
+            }
+            8 => {
+                let ([index, begin, length], _) = split_u32s(&payload)?;
+                Ok(PeerMessage::Cancel(index, begin, length))
+            }
+            20 if !payload.is_empty() => {
+                Ok(PeerMessage::Extended(payload[0], payload[1..].to_vec()))
+            }
+            _ => Err(anyhow!("Unknown message id: {id}")),
+        }
+    }
 }
 
 pub struct ConnectionState {
@@ -110,104 +247,16 @@ impl ConnectedPeer {
     }
 
     pub async fn send_message(&mut self, message: PeerMessage) -> Result<()> {
-        let mut buf = Vec::new();
-        match message {
-            PeerMessage::KeepAlive => {}
-            PeerMessage::Choke => buf.push(0),
-            PeerMessage::Unchoke => buf.push(1),
-            PeerMessage::Interested => buf.push(2),
-            PeerMessage::NotInterested => buf.push(3),
-            PeerMessage::Have(piece) => {
-                buf.push(4);
-                buf.extend(&piece.to_be_bytes());
-            }
-            PeerMessage::Bitfield(bitfield) => {
-                buf.push(5);
-                buf.extend(&bitfield);
-            }
-            PeerMessage::Request(index, begin, length) => {
-                buf.push(6);
-                buf.extend(&index.to_be_bytes());
-                buf.extend(&begin.to_be_bytes());
-                buf.extend(&length.to_be_bytes());
-            }
-            PeerMessage::Piece(index, begin, block) => {
-                buf.push(7);
-                buf.extend(&index.to_be_bytes());
-                buf.extend(&begin.to_be_bytes());
-                buf.extend(&block);
-            }
-            PeerMessage::Cancel(index, begin, length) => {
-                buf.push(8);
-                buf.extend(&index.to_be_bytes());
-                buf.extend(&begin.to_be_bytes());
-                buf.extend(&length.to_be_bytes());
-            }
-        }
-
-        let len = buf.len() as u32;
-        let len_buf = len.to_be_bytes();
-        let mut message_buf = Vec::new();
-        message_buf.extend(&len_buf);
-        message_buf.extend(&buf);
-
-        self.socket.write_all(&message_buf).await?;
-        self.socket.flush().await?;
-        Ok(())
+        message.write_to(&mut self.socket).await
     }
 
     pub async fn receive_message(&mut self) -> Result<PeerMessage> {
-        let mut len_buf = [0; 4];
-
-        self.socket.read_exact(&mut len_buf).await?;
-
-        let len = u32::from_be_bytes(len_buf) as usize;
-        if len == 0 {
-            return Ok(PeerMessage::KeepAlive);
-        }
-        let mut id_buf = [0; 1];
-        self.socket.read_exact(&mut id_buf).await?;
-        let id = id_buf[0];
-        let mut payload = vec![0; len - 1];
-        self.socket.read_exact(&mut payload).await?;
-        match id {
-            0 => Ok(PeerMessage::Choke),
-            1 => Ok(PeerMessage::Unchoke),
-            2 => Ok(PeerMessage::Interested),
-            3 => Ok(PeerMessage::NotInterested),
-            4 => {
-                let piece = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
-                Ok(PeerMessage::Have(piece))
-            }
-            5 => Ok(PeerMessage::Bitfield(payload)),
-            6 => {
-                let index = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
-                let begin = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
-                let length = u32::from_be_bytes([payload[8], payload[9], payload[10], payload[11]]);
-                Ok(PeerMessage::Request(index, begin, length))
-            }
-            7 => {
-                let index = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
-                let begin = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
-                Ok(PeerMessage::Piece(index, begin, payload[8..].to_vec()))
-            }
-            8 => {
-                let index = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
-                let begin = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
-                let length = u32::from_be_bytes([payload[8], payload[9], payload[10], payload[11]]);
-                Ok(PeerMessage::Cancel(index, begin, length))
-            }
-            _ => Err(anyhow!("Unknown message id: {}", id)),
-        }
+        PeerMessage::read_from(&mut self.socket).await
     }
 
     pub async fn download_piece(&mut self, piece_index: u32) -> Result<Vec<u8>> {
         println!("Downloading piece: {piece_index}");
-        let file_length = self.torrent.info.length;
-        let piece_length = min(
-            file_length - piece_index * self.torrent.info.piece_length,
-            self.torrent.info.piece_length,
-        );
+        let piece_length = self.torrent.piece_len(piece_index)?;
 
         let mut piece: Vec<u8> = Vec::with_capacity(piece_length as usize);
         let block_size = 2u32.pow(14);
@@ -228,9 +277,7 @@ impl ConnectedPeer {
             }
         }
 
-        let piece_index = piece_index as usize;
-        let piece_hash = &self.torrent.info.pieces[piece_index * 20..(piece_index + 1) * 20];
-        if !check_piece(&piece, piece_hash) {
+        if !check_piece(&piece, self.torrent.piece_hash(piece_index)?) {
             Err(anyhow!("Piece hash does not match"))?;
         }
 
@@ -238,37 +285,40 @@ impl ConnectedPeer {
     }
 }
 
-fn check_piece(piece: &[u8], piece_hash: &[u8]) -> bool {
+pub fn check_piece(piece: &[u8], piece_hash: &[u8]) -> bool {
     let mut hasher = Sha1::new();
     hasher.update(piece);
     let hash = hasher.finalize().to_vec();
     hash == piece_hash
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Peer {
-    ip: [u8; 4],
-    port: u16,
+    addr: SocketAddr,
 }
 
 impl Display for Peer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}:{}",
-            self.ip
-                .iter()
-                .map(std::string::ToString::to_string)
-                .collect::<Vec<String>>()
-                .join("."),
-            self.port
-        )
+        write!(f, "{}", self.addr)
+    }
+}
+
+impl FromStr for Peer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let addr = s
+            .parse::<SocketAddr>()
+            .map_err(|_| anyhow!("Invalid peer address: {s}"))?;
+        Ok(Self { addr })
     }
 }
 
 impl Peer {
-    pub fn new(ip: [u8; 4], port: u16) -> Self {
-        Self { ip, port }
+    pub fn new(ip: impl Into<IpAddr>, port: u16) -> Self {
+        Self {
+            addr: SocketAddr::new(ip.into(), port),
+        }
     }
 
     pub fn to_url(self) -> String {
@@ -276,37 +326,61 @@ impl Peer {
     }
 
     pub async fn connect(self, torrent: Arc<Torrent>) -> Result<ConnectedPeer> {
-        if let Ok(mut socket) = TcpStream::connect(self.to_url()).await {
-            let handshake = Handshake::new(torrent.info_hash(), "00112233445566778899".to_string());
-            socket.write_all(&handshake.to_bytes()).await?;
-            socket.flush().await?;
-            let mut buf = [0; 68];
-            socket.read_exact(&mut buf).await?;
-            let handshake = Handshake::from_buf(buf)?;
-            Ok(ConnectedPeer::new(socket, handshake.peer_id, self, torrent))
-        } else {
-            Err(anyhow!("Failed to connect to peer"))
+        let (socket, handshake) = self.handshake(&torrent.info_hash()).await?;
+        let peer_id = hex::encode(handshake.peer_id);
+        Ok(ConnectedPeer::new(socket, peer_id, self, torrent))
+    }
+
+    /// Connects and exchanges handshakes, checking that the peer serves
+    /// `info_hash`.
+    pub async fn handshake(self, info_hash: &[u8]) -> Result<(TcpStream, Handshake)> {
+        let Ok(mut socket) = TcpStream::connect(self.addr).await else {
+            return Err(anyhow!("Failed to connect to peer"));
+        };
+        let handshake = Handshake::new(info_hash.to_vec(), Identity::session().peer_id);
+        socket.write_all(&handshake.to_bytes()).await?;
+        socket.flush().await?;
+        let mut buf = [0; 68];
+        socket.read_exact(&mut buf).await?;
+        let handshake = Handshake::from_buf(buf)?;
+        if handshake.info_hash != info_hash {
+            return Err(anyhow!("Peer answered with a different info hash"));
         }
+        Ok((socket, handshake))
     }
 }
 
-struct Handshake {
-    info_hash: Vec<u8>,
-    peer_id: String,
+// The reserved bit announcing support for the extension protocol (BEP 10).
+const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+
+pub struct Handshake {
+    pub info_hash: Vec<u8>,
+    pub peer_id: [u8; 20],
+    reserved: [u8; 8],
 }
 
 impl Handshake {
-    fn new(info_hash: Vec<u8>, peer_id: String) -> Self {
-        Self { info_hash, peer_id }
+    fn new(info_hash: Vec<u8>, peer_id: [u8; 20]) -> Self {
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_PROTOCOL.0] |= EXTENSION_PROTOCOL.1;
+        Self {
+            info_hash,
+            peer_id,
+            reserved,
+        }
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL.0] & EXTENSION_PROTOCOL.1 != 0
     }
 
     fn to_bytes(&self) -> Vec<u8> {
         let mut buf = Vec::new();
         buf.push(19);
         buf.extend(b"BitTorrent protocol");
-        buf.extend(&[0; 8]);
+        buf.extend(&self.reserved);
         buf.extend(&self.info_hash);
-        buf.extend(self.peer_id.as_bytes());
+        buf.extend(self.peer_id);
         buf
     }
 
@@ -314,8 +388,41 @@ impl Handshake {
         if buf[0] != 19 {
             return Err(anyhow!("Invalid handshake"));
         }
+        let mut reserved = [0; 8];
+        reserved.copy_from_slice(&buf[20..28]);
         let info_hash = buf[28..48].to_vec();
-        let peer_id = hex::encode(&buf[48..68]);
-        Ok(Self { info_hash, peer_id })
+        let mut peer_id = [0; 20];
+        peer_id.copy_from_slice(&buf[48..68]);
+        Ok(Self {
+            info_hash,
+            peer_id,
+            reserved,
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(message: &[u8]) -> Result<PeerMessage> {
+        PeerMessage::read_from(&mut &message[..]).await
+    }
+
+    #[tokio::test]
+    async fn test_read_message() {
+        let message = read(b"\0\0\0\x0b\x07\0\0\0\x01\0\0\0\x02ab").await.unwrap();
+        assert!(matches!(message, PeerMessage::Piece(1, 2, block) if block == b"ab"));
+
+        // A Have without its piece index.
+        assert!(read(b"\0\0\0\x02\x04\0").await.is_err());
+        // A Request two bytes short.
+        assert!(read(b"\0\0\0\x0b\x06\0\0\0\x01\0\0\0\x02\0\0")
+            .await
+            .is_err());
+        // A 4 GiB message is refused before anything is allocated.
+        assert!(read(b"\xff\xff\xff\xff\x05").await.is_err());
     }
 }
@@ -0,0 +1,40 @@
+//! Randomness for ids, keys and shuffling, taken from the standard
+//! library so no RNG crate is needed.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+/// A random number from the standard library's per-process random hash
+/// keys, good enough for transaction ids and shuffling.
+pub fn random_u32() -> u32 {
+    let hash = RandomState::new().build_hasher().finish();
+    u32::try_from(hash >> 32).expect("the high half of a u64 fits a u32")
+}
+
+/// Fisher-Yates shuffle seeded from the standard library's per-process
+/// random hash keys, which is plenty for spreading load across trackers.
+pub fn shuffle<T>(items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(i);
+        let j = usize::try_from(hasher.finish() % (i as u64 + 1)).unwrap_or(0);
+        items.swap(i, j);
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shuffle_keeps_items() {
+        let mut items: Vec<u32> = (0..10).collect();
+        shuffle(&mut items);
+        items.sort_unstable();
+        assert_eq!(items, (0..10).collect::<Vec<_>>());
+    }
+}
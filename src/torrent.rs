@@ -1,30 +1,631 @@
-use bittorrent_starter_rust::mini_serde_bencode::to_bytes;
-use serde::{Deserialize, Serialize};
+use crate::{
+    bencode::BencodeValue,
+    mini_serde_bencode::{self, from_bytes, to_bytes, RawValue, WithRaw},
+    sha256,
+};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
+use std::{
+    collections::BTreeMap,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
 
+/// The size of the blocks hashed into the leaves of a v2 merkle tree.
+pub const V2_BLOCK_SIZE: u32 = 16 * 1024;
+
+/// A metainfo file. Keys of the outer dictionary that aren't modeled here
+/// are dropped when it is serialized; [`Torrent::encode_onto`] keeps them.
+/// `info` is kept as it was either way.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Torrent {
-    pub announce: String,
-    pub info: Info,
+    /// The tracker URL. Torrents with only an `announce-list`, and
+    /// trackerless ones, leave it out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce: Option<String>,
+    /// Tiers of tracker URLs (BEP 12). When present, `announce` is ignored.
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub announce_list: Vec<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+    /// Seconds since the Unix epoch.
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+    /// The character encoding of the strings in the torrent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    /// Web seed URLs (BEP 19), given either as one string or a list.
+    #[serde(
+        rename = "url-list",
+        default,
+        deserialize_with = "string_or_list",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub url_list: Vec<String>,
+    /// BEP 17 HTTP seeds, which serve pieces by index rather than files.
+    #[serde(
+        rename = "httpseeds",
+        default,
+        deserialize_with = "string_or_list",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub http_seeds: Vec<String>,
+    /// For v2 torrents, the hashes of each file's merkle tree layer whose
+    /// nodes cover one piece, keyed by the file's `pieces root`.
+    #[serde(
+        rename = "piece layers",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub piece_layers: BTreeMap<ByteBuf, ByteBuf>,
+    // Kept with its original bytes so the info hash covers every field, even
+    // those `Info` doesn't model.
+    pub info: WithRaw<Info>,
+    /// [`Info::files`], worked out on first use since per-piece lookups
+    /// need it over and over.
+    #[serde(skip)]
+    pub(crate) files: OnceLock<Vec<TorrentFile>>,
+}
+
+// The outer keys `Torrent` models, as named in its serde attributes;
+// `test_modeled_keys` checks that they match.
+const MODELED_KEYS: [&str; 10] = [
+    "announce",
+    "announce-list",
+    "comment",
+    "created by",
+    "creation date",
+    "encoding",
+    "url-list",
+    "httpseeds",
+    "piece layers",
+    "info",
+];
+
+// `info` always parses as `Info`, which only a dictionary can.
+const INFO_IS_DICT: &str = "info is a dictionary";
+
+/// Which versions of the protocol a torrent's metainfo supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaVersion {
+    V1,
+    V2,
+    /// Both a v1 `pieces` list and a v2 `file tree` describing the same
+    /// content (BEP 52 hybrid torrents).
+    Hybrid,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Info {
-    pub length: u32,
+    /// The file name, or the directory holding the files of a multi-file
+    /// torrent.
     pub name: String,
     #[serde(rename = "piece length")]
     pub piece_length: u32,
-    #[serde(with = "serde_bytes")]
+    /// The concatenated SHA-1 hashes of the pieces. Empty for v2-only
+    /// torrents.
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
     pub pieces: Vec<u8>,
+    /// Set on private torrents (BEP 27), whose peers must only come from
+    /// their trackers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<bool>,
+    /// Marks the torrent as belonging to a particular tracker, giving it a
+    /// distinct info hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// `2` for v2 and hybrid torrents (BEP 52).
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u32>,
+    /// The v2 description of the files.
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<FileTree>,
+    /// The v1 description of the files, absent from v2-only torrents.
+    #[serde(flatten, deserialize_with = "layout")]
+    pub layout: Option<Layout>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Layout {
+    SingleFile { length: u64 },
+    MultiFile { files: Vec<FileEntry> },
+}
+
+/// An entry of the `files` list in a multi-file torrent.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub length: u64,
+    /// The path below the torrent's directory, one element per component.
+    pub path: Vec<String>,
+    /// BEP 47 file attributes, one letter each; `p` marks a pad file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
+
+impl FileEntry {
+    /// Whether this is a BEP 47 pad file: zeros that align the next file to
+    /// a piece boundary, which are hashed but never written or fetched.
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_ref().is_some_and(|attr| attr.contains('p'))
+    }
+}
+
+/// A directory of a v2 `file tree`, mapping path components to files and
+/// subdirectories.
+pub type FileTree = BTreeMap<String, FileTreeNode>;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FileTreeNode {
+    /// A file, stored under the empty key.
+    File {
+        #[serde(rename = "")]
+        file: V2File,
+    },
+    Directory(FileTree),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct V2File {
+    pub length: u64,
+    /// The root of the file's SHA-256 merkle tree, absent for empty files.
+    #[serde(
+        rename = "pieces root",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pieces_root: Option<ByteBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PieceError {
+    #[error("piece {index} is out of range; the torrent has {count} pieces")]
+    OutOfRange { index: u32, count: u32 },
+    #[error("piece {0} has no SHA-1 hash")]
+    MissingHash(u32),
+    #[error("{0} pieces are more than a torrent can have")]
+    TooManyPieces(u64),
+}
+
+/// The part of a file that a piece covers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSlice {
+    /// The path relative to the download location, as in [`TorrentFile`].
+    pub path: PathBuf,
+    /// Where the slice starts within the file.
+    pub offset: u64,
+    pub length: u64,
+    /// Whether the file is a pad file, whose bytes are all zero.
+    pub padding: bool,
+}
+
+/// A file of the torrent's content, laid out as if all files were
+/// concatenated in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentFile {
+    /// The path relative to the download location, starting with the
+    /// torrent's name.
+    pub path: PathBuf,
+    pub length: u64,
+    /// Where the file starts in the concatenated content.
+    pub offset: u64,
+    /// Whether the file is a pad file, whose bytes are all zero.
+    pub padding: bool,
+}
+
+impl Info {
+    pub fn is_private(&self) -> bool {
+        self.private == Some(true)
+    }
+
+    pub fn version(&self) -> MetaVersion {
+        let v2 = self.meta_version == Some(2) && self.file_tree.is_some();
+        match (self.layout.is_some(), v2) {
+            (true, true) => MetaVersion::Hybrid,
+            (false, true) => MetaVersion::V2,
+            _ => MetaVersion::V1,
+        }
+    }
+
+    /// Whether the content is a single file named after the torrent rather
+    /// than a directory.
+    pub fn is_single_file(&self) -> bool {
+        match &self.layout {
+            Some(Layout::SingleFile { .. }) => true,
+            Some(Layout::MultiFile { .. }) => false,
+            None => matches!(&self.v2_files()[..], [(path, _)] if *path == Path::new(&self.name)),
+        }
+    }
+
+    /// The total size of the content in bytes.
+    pub fn length(&self) -> u64 {
+        self.files().iter().map(|file| file.length).sum()
+    }
+
+    /// The files of the content, from the v1 layout when there is one and
+    /// from the v2 file tree otherwise.
+    pub fn files(&self) -> Vec<TorrentFile> {
+        let files: Vec<(PathBuf, u64, bool)> = match &self.layout {
+            Some(Layout::SingleFile { length }) => {
+                vec![(PathBuf::from(&self.name), *length, false)]
+            }
+            Some(Layout::MultiFile { files }) => files
+                .iter()
+                .map(|file| {
+                    let mut path = PathBuf::from(&self.name);
+                    path.extend(&file.path);
+                    (path, file.length, file.is_padding())
+                })
+                .collect(),
+            None => self
+                .v2_files()
+                .into_iter()
+                .map(|(path, file)| (path, file.length, false))
+                .collect(),
+        };
+
+        let mut offset = 0;
+        files
+            .into_iter()
+            .map(|(path, length, padding)| {
+                let file = TorrentFile {
+                    path,
+                    length,
+                    offset,
+                    padding,
+                };
+                offset += length;
+                file
+            })
+            .collect()
+    }
+
+    /// The files of the v2 file tree in tree order, with their paths
+    /// relative to the download location.
+    pub fn v2_files(&self) -> Vec<(PathBuf, &V2File)> {
+        let Some(tree) = &self.file_tree else {
+            return Vec::new();
+        };
+        // A single file at the top of the tree is the torrent itself rather
+        // than a file inside a directory named after it.
+        if let [(_, FileTreeNode::File { file })] = tree.iter().collect::<Vec<_>>()[..] {
+            return vec![(PathBuf::from(&self.name), file)];
+        }
+        let mut files = Vec::new();
+        walk_file_tree(tree, PathBuf::from(&self.name), &mut files);
+        files
+    }
+}
+
+fn walk_file_tree<'a>(tree: &'a FileTree, dir: PathBuf, files: &mut Vec<(PathBuf, &'a V2File)>) {
+    for (name, node) in tree {
+        let path = dir.join(name);
+        match node {
+            FileTreeNode::File { file } => files.push((path, file)),
+            FileTreeNode::Directory(tree) => walk_file_tree(tree, path, files),
+        }
+    }
+}
+
+/// Computes the root of a v2 merkle tree from one of its layers, padding the
+/// layer to a power of two with `pad`, the hash of an all-zero subtree of the
+/// same height.
+pub fn merkle_root(layer: &[[u8; 32]], mut pad: [u8; 32]) -> [u8; 32] {
+    let mut layer = layer.to_vec();
+    if layer.is_empty() {
+        return pad;
+    }
+    while layer.len() > 1 {
+        if layer.len() % 2 == 1 {
+            layer.push(pad);
+        }
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        pad = hash_pair(&pad, &pad);
+    }
+    layer[0]
+}
+
+/// The hash of an all-zero subtree covering one piece, used to pad a piece
+/// layer. Leaves beyond the end of a file are zero rather than hashes of
+/// zero blocks.
+pub fn piece_layer_pad(piece_length: u32) -> [u8; 32] {
+    let mut pad = [0; 32];
+    let mut blocks = piece_length / V2_BLOCK_SIZE;
+    while blocks > 1 {
+        pad = hash_pair(&pad, &pad);
+        blocks /= 2;
+    }
+    pad
+}
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = sha256::Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize()
+}
+
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let urls = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    };
+    Ok(urls.into_iter().filter(|url| !url.is_empty()).collect())
+}
+
+/// Reads `length` or `files`, failing when either is malformed rather than
+/// leaving the layout out as an untagged `Option<Layout>` would.
+fn layout<'de, D>(deserializer: D) -> Result<Option<Layout>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Fields {
+        length: Option<u64>,
+        files: Option<Vec<FileEntry>>,
+    }
+
+    match Fields::deserialize(deserializer)? {
+        Fields {
+            length: Some(length),
+            files: None,
+        } => Ok(Some(Layout::SingleFile { length })),
+        Fields {
+            length: None,
+            files: Some(files),
+        } => Ok(Some(Layout::MultiFile { files })),
+        Fields {
+            length: None,
+            files: None,
+        } => Ok(None),
+        Fields { .. } => Err(de::Error::custom("both length and files are present")),
+    }
 }
 
 impl Torrent {
+    /// The tracker tiers to announce to, falling back to a single tier with
+    /// `announce` for torrents without a usable `announce-list`. Empty for
+    /// trackerless torrents.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self
+            .announce_list
+            .iter()
+            .map(|tier| {
+                tier.iter()
+                    .filter(|url| !url.is_empty())
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        if tiers.is_empty() {
+            self.announce
+                .iter()
+                .filter(|url| !url.is_empty())
+                .map(|url| vec![url.clone()])
+                .collect()
+        } else {
+            tiers
+        }
+    }
+
+    /// Replaces the trackers. The first URL becomes `announce`; when there
+    /// is more than one, the tiers are kept in `announce-list`.
+    pub fn set_trackers(&mut self, tiers: Vec<Vec<String>>) {
+        let tiers: Vec<Vec<String>> = tiers.into_iter().filter(|tier| !tier.is_empty()).collect();
+        self.announce = tiers.first().and_then(|tier| tier.first()).cloned();
+        self.announce_list = if tiers.iter().map(Vec::len).sum::<usize>() > 1 {
+            tiers
+        } else {
+            Vec::new()
+        };
+    }
+
+    /// Encodes the torrent in place of the metainfo it was parsed from: the
+    /// keys modeled here take their current values, and every other key of
+    /// `original`'s outer dictionary is kept byte for byte.
+    pub fn encode_onto(&self, original: &[u8]) -> mini_serde_bencode::Result<Vec<u8>> {
+        let mut outer = from_bytes::<BTreeMap<ByteBuf, RawValue>>(original)?;
+        outer.retain(|key, _| {
+            !MODELED_KEYS
+                .iter()
+                .any(|modeled| key[..] == *modeled.as_bytes())
+        });
+        outer.extend(from_bytes::<BTreeMap<ByteBuf, RawValue>>(&to_bytes(self)?)?);
+        to_bytes(&outer)
+    }
+
+    /// Edits the info dictionary as a bencode value, so fields [`Info`]
+    /// doesn't model survive, and parses the result back. This changes the
+    /// info hash.
+    pub fn edit_info(
+        &mut self,
+        edit: impl FnOnce(&mut BencodeValue),
+    ) -> mini_serde_bencode::Result<()> {
+        let mut info = from_bytes::<BencodeValue>(self.info.raw().get())?;
+        edit(&mut info);
+        self.info = from_bytes(&to_bytes(&info)?)?;
+        self.files = OnceLock::new();
+        Ok(())
+    }
+
+    /// Sets or clears the private flag, leaving the info dictionary alone
+    /// if it already matches.
+    pub fn set_private(&mut self, private: bool) -> mini_serde_bencode::Result<()> {
+        if self.info.is_private() == private {
+            return Ok(());
+        }
+        self.edit_info(|info| {
+            // Public torrents leave the key out rather than setting it to 0.
+            if private {
+                info.insert("private", 1).expect(INFO_IS_DICT);
+            } else {
+                info.remove("private");
+            }
+        })
+    }
+
+    /// Sets or clears the `source` tag, leaving the info dictionary alone if
+    /// it already matches.
+    pub fn set_source(&mut self, source: Option<String>) -> mini_serde_bencode::Result<()> {
+        if self.info.source == source {
+            return Ok(());
+        }
+        self.edit_info(|info| {
+            match source {
+                Some(source) => info.insert("source", source).expect(INFO_IS_DICT),
+                None => info.remove("source"),
+            };
+        })
+    }
+
+    /// The 20-byte info hash used with trackers and peers: the SHA-1 of the
+    /// info dictionary, or the truncated v2 hash for v2-only torrents.
     pub fn info_hash(&self) -> Vec<u8> {
-        let info = to_bytes(&self.info).unwrap();
+        match self.info.version() {
+            MetaVersion::V2 => self.info_hash_v2()[..20].to_vec(),
+            MetaVersion::V1 | MetaVersion::Hybrid => {
+                let mut hasher = Sha1::new();
+                hasher.update(self.info.raw().get());
+                hasher.finalize().to_vec()
+            }
+        }
+    }
+
+    /// The SHA-256 of the info dictionary, which identifies v2 and hybrid
+    /// torrents.
+    pub fn info_hash_v2(&self) -> [u8; 32] {
+        sha256::digest(self.info.raw().get())
+    }
 
-        let mut hasher = Sha1::new();
-        hasher.update(&info);
-        hasher.finalize().to_vec()
+    /// The files of the content, as [`Info::files`] lists them.
+    pub fn files(&self) -> &[TorrentFile] {
+        self.files.get_or_init(|| self.info.files())
+    }
+
+    /// The total size of the content in bytes.
+    pub fn length(&self) -> u64 {
+        self.files()
+            .last()
+            .map_or(0, |file| file.offset + file.length)
+    }
+
+    /// The number of pieces the content splits into.
+    pub fn piece_count(&self) -> Result<u32, PieceError> {
+        if self.info.piece_length == 0 {
+            return Ok(0);
+        }
+        let count = self.length().div_ceil(u64::from(self.info.piece_length));
+        u32::try_from(count).map_err(|_| PieceError::TooManyPieces(count))
+    }
+
+    /// The v1 SHA-1 hash of a piece.
+    pub fn piece_hash(&self, index: u32) -> Result<&[u8; 20], PieceError> {
+        self.check_index(index)?;
+        let start = index as usize * 20;
+        self.info
+            .pieces
+            .get(start..start + 20)
+            .and_then(|hash| hash.try_into().ok())
+            .ok_or(PieceError::MissingHash(index))
+    }
+
+    /// The byte range a piece covers in the concatenated content.
+    pub fn piece_range(&self, index: u32) -> Result<Range<u64>, PieceError> {
+        self.check_index(index)?;
+        let piece_length = u64::from(self.info.piece_length);
+        let start = u64::from(index) * piece_length;
+        Ok(start..(start + piece_length).min(self.length()))
+    }
+
+    /// The length of a piece; only the last one can be shorter than the
+    /// piece length.
+    pub fn piece_len(&self, index: u32) -> Result<u32, PieceError> {
+        let range = self.piece_range(index)?;
+        Ok(u32::try_from(range.end - range.start).expect("pieces fit the u32 piece length"))
+    }
+
+    /// The parts of files a piece covers, in order. Empty files are left
+    /// out.
+    pub fn files_for_piece(&self, index: u32) -> Result<Vec<FileSlice>, PieceError> {
+        let range = self.piece_range(index)?;
+        let files = self.files();
+        // Files are sorted by offset, so skip straight to the first one
+        // ending inside the piece.
+        let first = files.partition_point(|file| file.offset + file.length <= range.start);
+        Ok(files[first..]
+            .iter()
+            .take_while(|file| file.offset < range.end)
+            .filter(|file| file.length > 0)
+            .map(|file| {
+                let start = range.start.max(file.offset);
+                let end = range.end.min(file.offset + file.length);
+                FileSlice {
+                    path: file.path.clone(),
+                    offset: start - file.offset,
+                    length: end - start,
+                    padding: file.padding,
+                }
+            })
+            .collect())
+    }
+
+    fn check_index(&self, index: u32) -> Result<(), PieceError> {
+        let count = self.piece_count()?;
+        if index < count {
+            Ok(())
+        } else {
+            Err(PieceError::OutOfRange { index, count })
+        }
+    }
+
+    /// The piece layer of a v2 file, split into piece hashes. Files no
+    /// larger than a piece have none; their `pieces root` covers them.
+    pub fn piece_layer(&self, file: &V2File) -> Option<Vec<[u8; 32]>> {
+        let layer = self.piece_layers.get(file.pieces_root.as_ref()?)?;
+        if layer.len() % 32 != 0 {
+            return None;
+        }
+        Some(
+            layer
+                .chunks(32)
+                .map(|hash| hash.try_into().expect("chunks are 32 bytes"))
+                .collect(),
+        )
     }
 }
 
@@ -33,25 +634,396 @@ impl Torrent {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::mini_serde_bencode::from_str;
+    use crate::bencode::BencodeValue;
+    use crate::mini_serde_bencode::{from_bytes, from_str, to_bytes};
 
     #[test]
     fn test_de_torrent() {
         let input = "d8:announce39:http://torrent.ubuntu.com:6969/announce4:infod6:lengthi282334976e4:name28:ubuntu-20.04.1-desktop-amd6412:piece lengthi20e6:pieces22:0123456789abcdef012345ee";
         let torrent = from_str::<Torrent>(input).unwrap();
-        assert_eq!(torrent.announce, "http://torrent.ubuntu.com:6969/announce");
+        assert_eq!(
+            torrent.announce.as_deref(),
+            Some("http://torrent.ubuntu.com:6969/announce")
+        );
         assert_eq!(torrent.info.name, "ubuntu-20.04.1-desktop-amd64");
         assert_eq!(torrent.info.piece_length, 20);
         assert_eq!(torrent.info.pieces.len(), 22);
     }
 
+    #[test]
+    fn test_announce_list() {
+        let info = "d6:lengthi5e4:name4:spam12:piece lengthi20e6:pieces20:0123456789abcdef0123e";
+        let input = format!("d8:announce9:http://a/13:announce-listll9:http://b/9:http://c/elel0:el9:http://d/ee4:info{info}e");
+        let torrent = from_str::<Torrent>(&input).unwrap();
+        assert_eq!(
+            torrent.tracker_tiers(),
+            vec![vec!["http://b/", "http://c/"], vec!["http://d/"]]
+        );
+        assert_eq!(to_bytes(&torrent).unwrap(), input.as_bytes());
+
+        let input = format!("d8:announce9:http://a/4:info{info}e");
+        let torrent = from_str::<Torrent>(&input).unwrap();
+        assert_eq!(torrent.tracker_tiers(), vec![vec!["http://a/"]]);
+
+        let input = format!("d13:announce-listll9:http://b/ee4:info{info}e");
+        let torrent = from_str::<Torrent>(&input).unwrap();
+        assert_eq!(torrent.announce, None);
+        assert_eq!(torrent.tracker_tiers(), vec![vec!["http://b/"]]);
+        assert_eq!(to_bytes(&torrent).unwrap(), input.as_bytes());
+
+        let input = format!("d4:info{info}e");
+        let torrent = from_str::<Torrent>(&input).unwrap();
+        assert!(torrent.tracker_tiers().is_empty());
+    }
+
+    #[test]
+    fn test_optional_fields() {
+        let info = "d6:lengthi5e4:name4:spam12:piece lengthi20e6:pieces20:0123456789abcdef0123e";
+        let input = format!("d8:announce9:http://a/7:comment5:hello10:created by6:mktor413:creation datei1700000000e8:encoding5:UTF-84:info{info}e");
+        let torrent = from_str::<Torrent>(&input).unwrap();
+        assert_eq!(torrent.comment.as_deref(), Some("hello"));
+        assert_eq!(torrent.created_by.as_deref(), Some("mktor4"));
+        assert_eq!(torrent.creation_date, Some(1_700_000_000));
+        assert_eq!(torrent.encoding.as_deref(), Some("UTF-8"));
+        assert!(!torrent.info.is_private());
+        assert_eq!(to_bytes(&torrent).unwrap(), input.as_bytes());
+
+        let input = format!("d8:announce9:http://a/4:info{info}8:url-list9:http://w/e");
+        let torrent = from_str::<Torrent>(&input).unwrap();
+        assert_eq!(torrent.url_list, ["http://w/"]);
+
+        let input = format!("d8:announce9:http://a/4:info{info}8:url-listl9:http://w/0:ee");
+        let torrent = from_str::<Torrent>(&input).unwrap();
+        assert_eq!(torrent.url_list, ["http://w/"]);
+
+        let input = format!("d8:announce9:http://a/9:httpseedsl9:http://h/e4:info{info}e");
+        let torrent = from_str::<Torrent>(&input).unwrap();
+        assert_eq!(torrent.http_seeds, ["http://h/"]);
+        assert_eq!(to_bytes(&torrent).unwrap(), input.as_bytes());
+    }
+
     #[test]
     fn test_de_info() {
         let input = "d6:lengthi282334976e4:name28:ubuntu-20.04.1-desktop-amd6412:piece lengthi20e6:pieces22:0123456789abcdef012345e";
         let info = from_str::<Info>(input).unwrap();
-        assert_eq!(info.length, 282_334_976);
+        assert_eq!(info.length(), 282_334_976);
         assert_eq!(info.name, "ubuntu-20.04.1-desktop-amd64");
         assert_eq!(info.piece_length, 20);
         assert_eq!(info.pieces.len(), 22);
     }
+
+    #[test]
+    fn test_de_multi_file_info() {
+        let input = "d5:filesld6:lengthi5e4:pathl1:a5:b.txteed6:lengthi7e4:pathl5:c.txteee4:name4:spam12:piece lengthi20e6:pieces20:0123456789abcdef0123e";
+        let info = from_str::<Info>(input).unwrap();
+        assert_eq!(info.length(), 12);
+        assert_eq!(
+            info.files(),
+            vec![
+                TorrentFile {
+                    path: PathBuf::from("spam/a/b.txt"),
+                    length: 5,
+                    offset: 0,
+                    padding: false,
+                },
+                TorrentFile {
+                    path: PathBuf::from("spam/c.txt"),
+                    length: 7,
+                    offset: 5,
+                    padding: false,
+                },
+            ]
+        );
+        assert_eq!(to_bytes(&info).unwrap(), input.as_bytes());
+    }
+
+    #[test]
+    fn test_malformed_layout() {
+        let input = "d6:length1:54:name4:spam12:piece lengthi20e6:pieces20:0123456789abcdef0123e";
+        let err = from_str::<Info>(input).unwrap_err();
+        assert!(err.to_string().contains("expected u64"), "{err}");
+
+        let input =
+            "d5:filesd1:ai1ee4:name4:spam12:piece lengthi20e6:pieces20:0123456789abcdef0123e";
+        assert!(from_str::<Info>(input).is_err());
+
+        let input =
+            "d5:filesle6:lengthi5e4:name4:spam12:piece lengthi20e6:pieces20:0123456789abcdef0123e";
+        assert!(from_str::<Info>(input).is_err());
+
+        let input = "d4:name4:spam12:piece lengthi20e6:pieces20:0123456789abcdef0123e";
+        assert!(from_str::<Info>(input).unwrap().layout.is_none());
+    }
+
+    #[test]
+    fn test_pad_files() {
+        let input = "d5:filesld6:lengthi5e4:pathl1:aeed4:attr1:p6:lengthi11e4:pathl4:.pad2:11eed6:lengthi7e4:pathl1:beee4:name4:spam12:piece lengthi16e6:pieces40:0123456789abcdef01230123456789abcdef0123e";
+        let info = from_str::<Info>(input).unwrap();
+        let Some(Layout::MultiFile { files }) = &info.layout else {
+            panic!("expected a multi-file layout");
+        };
+        assert_eq!(files[1].attr.as_deref(), Some("p"));
+        assert!(files[1].is_padding());
+        assert!(!files[0].is_padding());
+        assert_eq!(
+            info.files()
+                .iter()
+                .map(|file| (file.offset, file.padding))
+                .collect::<Vec<_>>(),
+            [(0, false), (5, true), (16, false)]
+        );
+        assert_eq!(to_bytes(&info).unwrap(), input.as_bytes());
+    }
+
+    #[test]
+    fn test_info_hash_unmodeled_fields() {
+        let info = "d6:lengthi5e4:name4:spam12:piece lengthi20e6:pieces20:0123456789abcdef01237:privatei1e6:source3:foo12:x_cross_seed3:bare";
+        let input = format!("d8:announce9:http://a/4:info{info}e");
+        let torrent = from_str::<Torrent>(&input).unwrap();
+        assert!(torrent.info.is_private());
+        assert_eq!(torrent.info.source.as_deref(), Some("foo"));
+
+        let expected = Sha1::digest(info.as_bytes()).to_vec();
+        assert_eq!(torrent.info_hash(), expected);
+        assert_ne!(
+            Sha1::digest(to_bytes(&*torrent.info).unwrap()).to_vec(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_edit() {
+        let info = "d6:lengthi5e4:name4:spam12:piece lengthi20e6:pieces20:0123456789abcdef012312:x_cross_seed3:bare";
+        let input = format!("d8:announce9:http://a/4:info{info}e");
+        let mut torrent = from_str::<Torrent>(&input).unwrap();
+        let info_hash = torrent.info_hash();
+
+        torrent.set_trackers(vec![vec!["http://b/".to_string()]]);
+        torrent.set_private(false).unwrap();
+        torrent.set_source(None).unwrap();
+        assert_eq!(torrent.info_hash(), info_hash);
+        assert_eq!(
+            to_bytes(&torrent).unwrap(),
+            format!("d8:announce9:http://b/4:info{info}e").as_bytes()
+        );
+
+        torrent.set_trackers(vec![vec!["http://b/".to_string(), "http://c/".to_string()]]);
+        assert_eq!(
+            torrent.tracker_tiers(),
+            vec![vec!["http://b/", "http://c/"]]
+        );
+
+        torrent.set_private(true).unwrap();
+        assert!(torrent.info.is_private());
+        assert_ne!(torrent.info_hash(), info_hash);
+        assert!(torrent
+            .info
+            .raw()
+            .get()
+            .ends_with(b"7:privatei1e12:x_cross_seed3:bare"));
+        torrent.set_private(false).unwrap();
+        assert_eq!(torrent.info_hash(), info_hash);
+    }
+
+    #[test]
+    fn test_encode_onto() {
+        let info = "d6:lengthi5e4:name4:spam12:piece lengthi20e6:pieces20:0123456789abcdef0123e";
+        let input = format!(
+            "d8:announce9:http://a/7:comment2:hi4:info{info}5:nodesll1:hi1eee9:publisher3:bobe"
+        );
+        let mut torrent = from_str::<Torrent>(&input).unwrap();
+        torrent.set_trackers(vec![
+            vec!["http://b/".to_string()],
+            vec!["http://c/".to_string()],
+        ]);
+        torrent.comment = None;
+        torrent.url_list.push("http://w/".to_string());
+        assert_eq!(
+            torrent.encode_onto(input.as_bytes()).unwrap(),
+            format!("d8:announce9:http://b/13:announce-listll9:http://b/el9:http://c/ee4:info{info}5:nodesll1:hi1eee9:publisher3:bob8:url-listl9:http://w/ee").as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_modeled_keys() {
+        let input = "d8:announce9:http://a/4:infod6:lengthi5e4:name4:spam12:piece lengthi20e6:pieces20:0123456789abcdef0123ee";
+        let parsed = from_str::<Torrent>(input).unwrap();
+        // Every field is set, so each one shows up when serialized. Spelled out
+        // in full so that a new field has to be added here too.
+        let torrent = Torrent {
+            announce: Some("http://a/".to_string()),
+            announce_list: vec![vec!["http://a/".to_string()]],
+            comment: Some("hi".to_string()),
+            created_by: Some("me".to_string()),
+            creation_date: Some(0),
+            encoding: Some("UTF-8".to_string()),
+            url_list: vec!["http://w/".to_string()],
+            http_seeds: vec!["http://h/".to_string()],
+            piece_layers: BTreeMap::from([(ByteBuf::from("a"), ByteBuf::from("b"))]),
+            info: parsed.info,
+            files: OnceLock::new(),
+        };
+        let outer =
+            from_bytes::<BTreeMap<ByteBuf, RawValue>>(&to_bytes(&torrent).unwrap()).unwrap();
+        let mut modeled = MODELED_KEYS.map(str::as_bytes);
+        modeled.sort_unstable();
+        assert_eq!(
+            outer.keys().map(|key| &key[..]).collect::<Vec<_>>(),
+            modeled
+        );
+    }
+
+    #[test]
+    fn test_pieces() {
+        let input = "d8:announce9:http://a/4:infod5:filesld6:lengthi5e4:pathl1:aeed6:lengthi0e4:pathl1:beed6:lengthi30e4:pathl1:ceee4:name4:spam12:piece lengthi16e6:pieces60:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbcccccccccccccccccccce12:piece layersdee";
+        let torrent = from_str::<Torrent>(input).unwrap();
+        assert_eq!(torrent.piece_count(), Ok(3));
+        assert_eq!(torrent.piece_hash(1).unwrap(), b"bbbbbbbbbbbbbbbbbbbb");
+        assert_eq!(torrent.piece_range(2), Ok(32..35));
+        assert_eq!(torrent.piece_len(0), Ok(16));
+        assert_eq!(torrent.piece_len(2), Ok(3));
+        assert_eq!(
+            torrent.files_for_piece(0).unwrap(),
+            vec![
+                FileSlice {
+                    path: PathBuf::from("spam/a"),
+                    offset: 0,
+                    length: 5,
+                    padding: false,
+                },
+                FileSlice {
+                    path: PathBuf::from("spam/c"),
+                    offset: 0,
+                    length: 11,
+                    padding: false,
+                },
+            ]
+        );
+        assert_eq!(
+            torrent.piece_len(3),
+            Err(PieceError::OutOfRange { index: 3, count: 3 })
+        );
+        assert_eq!(
+            torrent.files_for_piece(2).unwrap(),
+            vec![FileSlice {
+                path: PathBuf::from("spam/c"),
+                offset: 27,
+                length: 3,
+                padding: false,
+            }]
+        );
+
+        let input = "d8:announce9:http://a/4:infod6:lengthi8589934592e4:name4:spam12:piece lengthi1e6:pieces0:ee";
+        let torrent = from_str::<Torrent>(input).unwrap();
+        assert_eq!(
+            torrent.piece_count(),
+            Err(PieceError::TooManyPieces(8_589_934_592))
+        );
+    }
+
+    fn v2_info(with_v1: bool) -> BencodeValue {
+        let file = |length: u64, root: u8| {
+            BencodeValue::dict()
+                .entry(
+                    "",
+                    BencodeValue::dict()
+                        .entry("length", length)
+                        .entry("pieces root", vec![root; 32])
+                        .build(),
+                )
+                .build()
+        };
+        let mut info = BencodeValue::dict()
+            .entry("name", "spam")
+            .entry("piece length", 16384)
+            .entry("meta version", 2)
+            .entry(
+                "file tree",
+                BencodeValue::dict()
+                    .entry("a.txt", file(20_000, 1))
+                    .entry("b", BencodeValue::dict().entry("c.txt", file(5, 2)).build())
+                    .build(),
+            )
+            .build();
+        if with_v1 {
+            info.insert("pieces", vec![0; 40]).unwrap();
+            info.insert(
+                "files",
+                vec![
+                    BencodeValue::dict()
+                        .entry("length", 20_000)
+                        .entry("path", ["a.txt"].into_iter().collect::<BencodeValue>())
+                        .build(),
+                    BencodeValue::dict()
+                        .entry("length", 5)
+                        .entry("path", ["b", "c.txt"].into_iter().collect::<BencodeValue>())
+                        .build(),
+                ],
+            )
+            .unwrap();
+        }
+        info
+    }
+
+    #[test]
+    fn test_v2_torrent() {
+        let info = v2_info(false);
+        let torrent = BencodeValue::dict()
+            .entry("announce", "http://a/")
+            .entry("info", info.clone())
+            .entry(
+                "piece layers",
+                BencodeValue::dict().entry(vec![1; 32], vec![7; 64]).build(),
+            )
+            .build();
+        let torrent = from_bytes::<Torrent>(&to_bytes(&torrent).unwrap()).unwrap();
+        assert_eq!(torrent.info.version(), MetaVersion::V2);
+        assert!(!torrent.info.is_single_file());
+        assert_eq!(torrent.info.length(), 20_005);
+        let files = torrent.info.files();
+        assert_eq!(files[1].path, Path::new("spam/b/c.txt"));
+        assert_eq!(files[1].offset, 20_000);
+
+        let info_bytes = to_bytes(&info).unwrap();
+        assert_eq!(torrent.info_hash_v2(), sha256::digest(&info_bytes));
+        assert_eq!(torrent.info_hash(), torrent.info_hash_v2()[..20]);
+
+        let v2_files = torrent.info.v2_files();
+        assert_eq!(torrent.piece_layer(v2_files[0].1), Some(vec![[7; 32]; 2]));
+        assert_eq!(torrent.piece_layer(v2_files[1].1), None);
+    }
+
+    #[test]
+    fn test_hybrid_torrent() {
+        let info = v2_info(true);
+        let torrent = BencodeValue::dict()
+            .entry("announce", "http://a/")
+            .entry("info", info.clone())
+            .build();
+        let torrent = from_bytes::<Torrent>(&to_bytes(&torrent).unwrap()).unwrap();
+        assert_eq!(torrent.info.version(), MetaVersion::Hybrid);
+        assert_eq!(torrent.info.length(), 20_005);
+        let info_bytes = to_bytes(&info).unwrap();
+        assert_eq!(torrent.info_hash(), Sha1::digest(&info_bytes).to_vec());
+        assert_eq!(torrent.info_hash_v2(), sha256::digest(&info_bytes));
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        // A file of five blocks with 32 KiB pieces: the leaves are padded
+        // with zeros to eight, the piece layer has three nodes.
+        let leaves: Vec<[u8; 32]> = (0..5u8).map(|i| sha256::digest(&[i; 16])).collect();
+        let zero = [0; 32];
+        let layer = [
+            hash_pair(&leaves[0], &leaves[1]),
+            hash_pair(&leaves[2], &leaves[3]),
+            hash_pair(&leaves[4], &zero),
+        ];
+        let expected = hash_pair(
+            &hash_pair(&layer[0], &layer[1]),
+            &hash_pair(&layer[2], &hash_pair(&zero, &zero)),
+        );
+        assert_eq!(merkle_root(&leaves, zero), expected);
+        assert_eq!(merkle_root(&layer, piece_layer_pad(32 * 1024)), expected);
+    }
 }
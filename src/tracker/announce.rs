@@ -0,0 +1,231 @@
+//! Keeping a torrent's trackers informed over the course of a download.
+
+use anyhow::Result;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{sync::oneshot, task::JoinHandle, time::timeout};
+
+use super::{Event, TrackerRequest, Trackers};
+use crate::peer::Peer;
+
+// Used until a tracker says otherwise.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+// How long each of the final `completed` and `stopped` announces may take,
+// so that a dead tracker doesn't keep us from exiting.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Transfer counters the download engine updates and the announcer
+/// reports.
+#[derive(Debug)]
+pub struct TransferStats {
+    downloaded: AtomicU64,
+    left: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> Self {
+        Self {
+            downloaded: AtomicU64::new(0),
+            left: AtomicU64::new(left),
+        }
+    }
+
+    /// Records a verified piece of `bytes` bytes.
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+        let _ = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(bytes))
+            });
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+}
+
+/// Announces a torrent to its trackers: `started` first, then regularly at
+/// the interval the tracker asks for, and `completed` and `stopped` at the
+/// end.
+#[derive(Debug)]
+pub struct Announcer {
+    trackers: Trackers,
+    info_hash: Vec<u8>,
+    stats: Arc<TransferStats>,
+    interval: Duration,
+}
+
+impl Announcer {
+    pub fn new(info_hash: Vec<u8>, tiers: Vec<Vec<String>>, stats: Arc<TransferStats>) -> Self {
+        Self {
+            trackers: Trackers::new(tiers),
+            info_hash,
+            stats,
+            interval: DEFAULT_INTERVAL,
+        }
+    }
+
+    /// Sends an announce with the current counters and returns the peers the
+    /// tracker knows of.
+    pub async fn announce(&mut self, event: Option<Event>) -> Result<Vec<Peer>> {
+        let mut request = TrackerRequest::new(self.info_hash.clone(), self.stats.left());
+        // This client doesn't upload yet, so `uploaded` stays 0.
+        request.downloaded = self.stats.downloaded();
+        request.event = event;
+
+        let response = self.trackers.announce(&request).await?;
+        if let (Some(seeders), Some(leechers)) = (response.seeders, response.leechers) {
+            println!("Tracker reports {seeders} seeders and {leechers} leechers");
+        }
+        if !response.interval.is_zero() {
+            self.interval = response.interval;
+        }
+        if let Some(min_interval) = response.min_interval {
+            self.interval = self.interval.max(min_interval);
+        }
+        Ok(response.peers)
+    }
+
+    /// Waits out the interval and sends a regular announce.
+    async fn reannounce(&mut self) {
+        tokio::time::sleep(self.interval).await;
+        if let Err(e) = self.announce(None).await {
+            println!("Re-announce failed: {e}");
+        }
+    }
+
+    /// Re-announces in the background until the returned handle is stopped.
+    pub fn spawn(mut self) -> AnnouncerHandle {
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let completed = loop {
+                // Stopping cancels a re-announce that is still in flight.
+                tokio::select! {
+                    completed = &mut stopped => break completed.unwrap_or(false),
+                    () = self.reannounce() => {}
+                }
+            };
+            if self.trackers.is_empty() {
+                return;
+            }
+            let events = if completed {
+                &[Event::Completed, Event::Stopped][..]
+            } else {
+                &[Event::Stopped]
+            };
+            for event in events {
+                match timeout(SHUTDOWN_TIMEOUT, self.announce(Some(*event))).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => println!("Announcing {} failed: {e}", event.as_str()),
+                    Err(_) => println!("Announcing {} timed out", event.as_str()),
+                }
+            }
+        });
+        AnnouncerHandle { stop, task }
+    }
+}
+
+#[derive(Debug)]
+pub struct AnnouncerHandle {
+    stop: oneshot::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl AnnouncerHandle {
+    /// Stops re-announcing and tells the trackers, announcing `completed`
+    /// before `stopped` if the download finished.
+    pub async fn stop(self, completed: bool) {
+        let _ = self.stop.send(completed);
+        let _ = self.task.await;
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    // An HTTP tracker stand-in that sends the query of each announce down
+    // `queries` and asks for re-announces every second.
+    async fn serve(listener: TcpListener, queries: mpsc::UnboundedSender<String>) {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                if socket.read(&mut byte).await.unwrap() == 0 {
+                    break;
+                }
+                request.push(byte[0]);
+            }
+            let request = String::from_utf8(request).unwrap();
+            let target = request.split(' ').nth(1).unwrap();
+            let (_, query) = target.split_once('?').unwrap();
+            queries.send(query.to_string()).unwrap();
+
+            let body = b"d8:intervali1e12:min intervali1e5:peers6:\x0a\x00\x00\x01\x1a\xe1e";
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(body).await.unwrap();
+        }
+    }
+
+    fn param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tracker = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (queries, mut received) = mpsc::unbounded_channel();
+        tokio::spawn(serve(listener, queries));
+
+        let stats = Arc::new(TransferStats::new(10));
+        let mut announcer = Announcer::new(vec![1; 20], vec![vec![tracker]], Arc::clone(&stats));
+        let peers = announcer.announce(Some(Event::Started)).await.unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(announcer.interval, Duration::from_secs(1));
+        let query = received.recv().await.unwrap();
+        assert_eq!(param(&query, "event"), Some("started"));
+        assert_eq!(param(&query, "left"), Some("10"));
+
+        let handle = announcer.spawn();
+        stats.add_downloaded(10);
+        let query = received.recv().await.unwrap();
+        assert_eq!(param(&query, "event"), None);
+        assert_eq!(param(&query, "downloaded"), Some("10"));
+        assert_eq!(param(&query, "left"), Some("0"));
+
+        handle.stop(true).await;
+        let events: Vec<_> = [received.recv().await, received.recv().await]
+            .into_iter()
+            .map(|query| param(&query.unwrap(), "event").map(str::to_string))
+            .collect();
+        assert_eq!(
+            events,
+            [Some("completed".to_string()), Some("stopped".to_string())]
+        );
+    }
+}
@@ -0,0 +1,660 @@
+use anyhow::{anyhow, Result};
+use bittorrent_starter_rust::{
+    bencode::BencodeValue,
+    mini_serde_bencode::{self, from_bytes},
+    torrent::Torrent,
+};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use std::{collections::HashMap, future::Future, net::IpAddr, sync::Mutex, time::Duration};
+
+use crate::{identity::Identity, peer::Peer, random::shuffle};
+
+mod announce;
+mod udp;
+
+pub use announce::{Announcer, TransferStats};
+use udp::UdpTrackerClient;
+
+/// The `event` of an announce. Regular announces have none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl Event {
+    fn as_str(self) -> &'static str {
+        match self {
+            Event::Started => "started",
+            Event::Completed => "completed",
+            Event::Stopped => "stopped",
+        }
+    }
+
+    /// The event's number in UDP announces, where 0 means none.
+    fn udp_code(self) -> u32 {
+        match self {
+            Event::Completed => 1,
+            Event::Started => 2,
+            Event::Stopped => 3,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TrackerRequest {
+    info_hash: Vec<u8>,
+    peer_id: [u8; 20],
+    port: u16,
+    key: u32,
+    numwant: Option<u32>,
+    ip: Option<IpAddr>,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    compact: u8,
+    event: Option<Event>,
+}
+
+impl TrackerRequest {
+    /// A request on behalf of the session's [`Identity`].
+    fn new(info_hash: Vec<u8>, left: u64) -> Self {
+        let identity = Identity::session();
+        Self {
+            info_hash,
+            peer_id: identity.peer_id,
+            port: identity.port,
+            key: identity.key,
+            numwant: identity.numwant,
+            ip: identity.ip,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            compact: 1,
+            event: None,
+        }
+    }
+
+    fn to_url(&self, tracker: &str) -> String {
+        // Passkey trackers carry their own query, which ours extends.
+        let separator = if tracker.contains('?') { '&' } else { '?' };
+        let mut url = format!(
+            "{}{separator}info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact={}&key={:08X}",
+            tracker,
+            percent_encode(&self.info_hash),
+            percent_encode(&self.peer_id),
+            self.port,
+            self.uploaded,
+            self.downloaded,
+            self.left,
+            self.compact,
+            self.key
+        );
+        if let Some(event) = self.event {
+            url.push_str("&event=");
+            url.push_str(event.as_str());
+        }
+        if let Some(numwant) = self.numwant {
+            url.push_str("&numwant=");
+            url.push_str(&numwant.to_string());
+        }
+        if let Some(ip) = self.ip {
+            url.push_str("&ip=");
+            url.push_str(&percent_encode(ip.to_string().as_bytes()));
+        }
+        url
+    }
+}
+
+/// Why a tracker didn't give us peers.
+#[derive(Debug, thiserror::Error)]
+pub enum TrackerError {
+    /// The tracker answered with a `failure reason`, or a UDP error.
+    #[error("tracker refused the announce: {0}")]
+    Failure(String),
+    #[error("tracker answered with HTTP status {0}")]
+    Status(StatusCode),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error("invalid tracker response: {0}")]
+    InvalidResponse(#[from] mini_serde_bencode::Error),
+}
+
+#[derive(Debug, Deserialize)]
+struct TrackerResponse {
+    /// When present, the announce failed and nothing else is set.
+    #[serde(rename = "failure reason", default)]
+    failure_reason: Option<String>,
+    /// Like a failure, but the rest of the response is valid.
+    #[serde(rename = "warning message", default)]
+    warning_message: Option<String>,
+    #[serde(default)]
+    interval: u64,
+    #[serde(rename = "min interval", default)]
+    min_interval: Option<u64>,
+    /// To be sent back as `trackerid` in later announces.
+    #[serde(rename = "tracker id", default)]
+    tracker_id: Option<String>,
+    /// The number of seeders.
+    #[serde(default)]
+    complete: Option<u32>,
+    /// The number of leechers.
+    #[serde(default)]
+    incomplete: Option<u32>,
+    #[serde(default)]
+    peers: PeerList,
+    /// IPv6 peers in the compact form (BEP 7).
+    #[serde(default, with = "serde_bytes")]
+    peers6: Vec<u8>,
+}
+
+/// The two forms of `peers`: a compact string, or a list of dictionaries.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PeerList {
+    Compact(#[serde(with = "serde_bytes")] Vec<u8>),
+    Dictionaries(Vec<PeerEntry>),
+}
+
+impl Default for PeerList {
+    fn default() -> Self {
+        PeerList::Compact(Vec::new())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerEntry {
+    /// An IPv4 or IPv6 address, or a DNS name.
+    ip: String,
+    port: u16,
+}
+
+impl TrackerResponse {
+    /// Turns a successful response into an [`AnnounceResponse`], resolving
+    /// peers given by name.
+    async fn into_announce(self) -> Result<AnnounceResponse, TrackerError> {
+        if let Some(reason) = self.failure_reason {
+            return Err(TrackerError::Failure(reason));
+        }
+        if let Some(warning) = &self.warning_message {
+            println!("Tracker warning: {warning}");
+        }
+
+        let mut peers = match self.peers {
+            PeerList::Compact(peers) => compact_peers(&peers),
+            PeerList::Dictionaries(entries) => {
+                let mut peers = Vec::new();
+                for entry in entries {
+                    if let Ok(ip) = entry.ip.parse::<IpAddr>() {
+                        peers.push(Peer::new(ip, entry.port));
+                    } else if let Ok(mut addrs) =
+                        tokio::net::lookup_host((entry.ip.as_str(), entry.port)).await
+                    {
+                        peers.extend(addrs.next().map(|addr| Peer::new(addr.ip(), addr.port())));
+                    }
+                }
+                peers
+            }
+        };
+        peers.extend(compact_peers6(&self.peers6));
+
+        Ok(AnnounceResponse {
+            peers,
+            interval: Duration::from_secs(self.interval),
+            min_interval: self.min_interval.map(Duration::from_secs),
+            seeders: self.complete,
+            leechers: self.incomplete,
+        })
+    }
+}
+
+/// What an announce returns, whichever protocol the tracker speaks.
+#[derive(Debug)]
+pub struct AnnounceResponse {
+    pub peers: Vec<Peer>,
+    /// How long to wait before the next regular announce.
+    pub interval: Duration,
+    /// Announces must not come more often than this.
+    pub min_interval: Option<Duration>,
+    pub seeders: Option<u32>,
+    pub leechers: Option<u32>,
+}
+
+/// A torrent's counts as reported by a scrape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    /// Peers with the whole torrent.
+    pub complete: u32,
+    /// Peers still downloading.
+    pub incomplete: u32,
+    /// How many times the torrent has been downloaded in full.
+    pub downloaded: u32,
+}
+
+/// Parses peers in the compact form: four bytes of IPv4 address and two of
+/// port each.
+fn compact_peers(peers: &[u8]) -> Vec<Peer> {
+    peers
+        .chunks_exact(6)
+        .map(|chunk| {
+            let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+            Peer::new([chunk[0], chunk[1], chunk[2], chunk[3]], port)
+        })
+        .collect()
+}
+
+/// Parses IPv6 peers in the compact form: sixteen bytes of address and two
+/// of port each.
+fn compact_peers6(peers: &[u8]) -> Vec<Peer> {
+    peers
+        .chunks_exact(18)
+        .map(|chunk| {
+            let ip: [u8; 16] = chunk[..16].try_into().expect("chunks are 18 bytes");
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+            Peer::new(ip, port)
+        })
+        .collect()
+}
+
+/// A torrent's trackers, tried in the order BEP 12 describes: each tier is
+/// shuffled once, trackers are tried tier by tier, and a tracker that
+/// answers moves to the front of its tier for the next announce.
+#[derive(Debug)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerTiers {
+    pub fn new(mut tiers: Vec<Vec<String>>) -> Self {
+        for tier in &mut tiers {
+            shuffle(tier);
+        }
+        Self { tiers }
+    }
+
+    /// Calls `announce` with each tracker in turn until one succeeds.
+    pub async fn try_each<T, F, Fut>(&mut self, mut announce: F) -> Result<T>
+    where
+        F: FnMut(String) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut last_error = None;
+        for tier in &mut self.tiers {
+            for i in 0..tier.len() {
+                match announce(tier[i].clone()).await {
+                    Ok(t) => {
+                        let tracker = tier.remove(i);
+                        tier.insert(0, tracker);
+                        return Ok(t);
+                    }
+                    Err(e) => {
+                        println!("Tracker {} failed: {e}", tier[i]);
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("No trackers to announce to")))
+    }
+}
+
+/// A torrent's trackers along with the clients to reach them over HTTP and
+/// UDP.
+#[derive(Debug)]
+pub struct Trackers {
+    tiers: TrackerTiers,
+    client: Client,
+    udp: UdpTrackerClient,
+    /// The `tracker id` each HTTP tracker last gave, by URL.
+    tracker_ids: Mutex<HashMap<String, String>>,
+}
+
+impl Trackers {
+    /// Empty URLs and tiers are dropped.
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        let tiers = tiers
+            .into_iter()
+            .map(|tier| tier.into_iter().filter(|url| !url.is_empty()).collect())
+            .filter(|tier: &Vec<String>| !tier.is_empty())
+            .collect();
+        Self {
+            tiers: TrackerTiers::new(tiers),
+            client: Client::new(),
+            udp: UdpTrackerClient::new(),
+            tracker_ids: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.tiers.is_empty()
+    }
+
+    async fn announce(&mut self, request: &TrackerRequest) -> Result<AnnounceResponse> {
+        let (client, udp, tracker_ids) = (&self.client, &self.udp, &self.tracker_ids);
+        self.tiers
+            .try_each(|tracker| async move {
+                if tracker.starts_with("udp://") {
+                    return udp.announce(&tracker, request).await;
+                }
+                let mut url = request.to_url(&tracker);
+                let tracker_id = tracker_ids
+                    .lock()
+                    .expect("tracker id map poisoned")
+                    .get(&tracker)
+                    .cloned();
+                if let Some(tracker_id) = tracker_id {
+                    url.push_str("&trackerid=");
+                    url.push_str(&percent_encode(tracker_id.as_bytes()));
+                }
+
+                let response = announce_http(client, &url).await?;
+                if let Some(tracker_id) = &response.tracker_id {
+                    tracker_ids
+                        .lock()
+                        .expect("tracker id map poisoned")
+                        .insert(tracker, tracker_id.clone());
+                }
+                Ok(response.into_announce().await?)
+            })
+            .await
+    }
+
+    /// Fetches the counts of each of `info_hashes` in one request, in the
+    /// same order. `None` means the tracker doesn't know the torrent.
+    pub async fn scrape(&mut self, info_hashes: &[&[u8]]) -> Result<Vec<Option<ScrapeStats>>> {
+        let (client, udp) = (&self.client, &self.udp);
+        self.tiers
+            .try_each(|tracker| async move {
+                if tracker.starts_with("udp://") {
+                    let stats = udp.scrape(&tracker, info_hashes).await?;
+                    return Ok(stats.into_iter().map(Some).collect());
+                }
+                let url = scrape_url(&tracker)
+                    .ok_or_else(|| anyhow!("Tracker does not support scrape"))?;
+                Ok(scrape_http(client, &url, info_hashes).await?)
+            })
+            .await
+    }
+}
+
+/// The scrape URL of an HTTP tracker: by convention, the announce URL with
+/// the `announce` starting its last path segment replaced by `scrape`.
+/// Trackers whose URL doesn't follow the pattern don't support scrape.
+fn scrape_url(announce: &str) -> Option<String> {
+    let (path, query) = announce.split_once('?').unwrap_or((announce, ""));
+    let slash = path.rfind('/')?;
+    let rest = path[slash + 1..].strip_prefix("announce")?;
+    let mut url = format!("{}scrape{rest}", &path[..=slash]);
+    if !query.is_empty() {
+        url.push('?');
+        url.push_str(query);
+    }
+    Some(url)
+}
+
+async fn scrape_http(
+    client: &Client,
+    url: &str,
+    info_hashes: &[&[u8]],
+) -> Result<Vec<Option<ScrapeStats>>, TrackerError> {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let query: Vec<String> = info_hashes
+        .iter()
+        .map(|hash| format!("info_hash={}", percent_encode(hash)))
+        .collect();
+    let response = client
+        .get(format!("{url}{separator}{}", query.join("&")))
+        .send()
+        .await?;
+    let status = response.status();
+    let body = response.bytes().await?;
+    let response = match from_bytes::<BencodeValue>(&body) {
+        Ok(response) => response,
+        Err(_) if !status.is_success() => return Err(TrackerError::Status(status)),
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(reason) = response.get("failure reason") {
+        let reason = String::from_utf8_lossy(reason.as_bytes().unwrap_or_default());
+        return Err(TrackerError::Failure(reason.into_owned()));
+    }
+
+    // `files` maps each info hash the tracker knows to its counts.
+    let files = response.get("files");
+    let count = |stats: &BencodeValue, key: &str| {
+        let count = stats.get(key).and_then(BencodeValue::as_int);
+        count
+            .and_then(|count| u32::try_from(count).ok())
+            .unwrap_or(0)
+    };
+    Ok(info_hashes
+        .iter()
+        .map(|hash| {
+            let counts = files?.get(hash)?;
+            Some(ScrapeStats {
+                complete: count(counts, "complete"),
+                incomplete: count(counts, "incomplete"),
+                downloaded: count(counts, "downloaded"),
+            })
+        })
+        .collect())
+}
+
+async fn announce_http(client: &Client, url: &str) -> Result<TrackerResponse, TrackerError> {
+    let response = client.get(url).send().await?;
+    let status = response.status();
+    let body = response.bytes().await?;
+    // Failures often come with an error status, but their body says more.
+    match from_bytes::<TrackerResponse>(&body) {
+        Ok(response) => Ok(response),
+        Err(_) if !status.is_success() => Err(TrackerError::Status(status)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn discover_peers(torrent: &Torrent) -> Result<Vec<Peer>> {
+    discover_peers_for(
+        torrent.info_hash(),
+        torrent.tracker_tiers(),
+        torrent.length(),
+    )
+    .await
+}
+
+/// Asks the trackers in `tiers` for peers of the torrent with `info_hash`,
+/// reporting `left` bytes still to download.
+pub async fn discover_peers_for(
+    info_hash: Vec<u8>,
+    tiers: Vec<Vec<String>>,
+    left: u64,
+) -> Result<Vec<Peer>> {
+    let request = TrackerRequest::new(info_hash, left);
+    Ok(Trackers::new(tiers).announce(&request).await?.peers)
+}
+
+pub fn percent_encode(input: &[u8]) -> String {
+    let mut output = String::with_capacity(input.len() * 3);
+    for &byte in input {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                output.push(byte as char);
+            }
+            byte => {
+                output.push('%');
+                output.push(hex_chars(byte >> 4));
+                output.push(hex_chars(byte & 0x0F));
+            }
+        }
+    }
+    output
+}
+
+fn hex_chars(byte: u8) -> char {
+    match byte {
+        0..=9 => (byte + b'0') as char,
+        10..=15 => (byte - 10 + b'A') as char,
+        _ => unreachable!(),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[tokio::test]
+    async fn test_try_each_promotes_working_tracker() {
+        let mut tiers = TrackerTiers {
+            tiers: vec![
+                vec!["a".to_string(), "b".to_string()],
+                vec!["c".to_string()],
+            ],
+        };
+
+        let mut tried = Vec::new();
+        let tracker = tiers
+            .try_each(|tracker| {
+                tried.push(tracker.clone());
+                async move {
+                    if tracker == "b" {
+                        Ok(tracker)
+                    } else {
+                        Err(anyhow!("unreachable"))
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(tracker, "b");
+        assert_eq!(tried, ["a", "b"]);
+        assert_eq!(tiers.tiers[0], ["b", "a"]);
+
+        let err = tiers
+            .try_each(|_| async { Err::<(), _>(anyhow!("unreachable")) })
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "unreachable");
+    }
+
+    async fn parse(response: &[u8]) -> Result<AnnounceResponse, TrackerError> {
+        from_bytes::<TrackerResponse>(response)?
+            .into_announce()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_response_peers() {
+        let response = parse(
+            b"d8:completei5e10:incompletei3e8:intervali1800e5:peersld2:ip8:10.0.0.14:porti6881eed2:ip3:::14:porti6882eee6:peers618:\x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe3e",
+        )
+        .await
+        .unwrap();
+        let peers: Vec<String> = response.peers.iter().map(ToString::to_string).collect();
+        assert_eq!(peers, ["10.0.0.1:6881", "[::1]:6882", "[2001:db8::1]:6883"]);
+        assert_eq!(response.interval, Duration::from_secs(1800));
+        assert_eq!((response.seeders, response.leechers), (Some(5), Some(3)));
+
+        let response = parse(b"d8:intervali60e5:peers6:\x7f\0\0\x01\x1a\xe1e")
+            .await
+            .unwrap();
+        assert_eq!(response.peers[0].to_string(), "127.0.0.1:6881");
+        assert_eq!(response.seeders, None);
+    }
+
+    #[test]
+    fn test_request_url() {
+        let mut request = TrackerRequest::new(vec![0xAB; 20], 100);
+        request.peer_id = *b"-XX0001-\x00\x01\x02\x03abcdefgh";
+        request.key = 0x1234_ABCD;
+        request.numwant = Some(50);
+        request.ip = Some(IpAddr::from([10, 0, 0, 1]));
+        request.event = Some(Event::Started);
+        assert_eq!(
+            request.to_url("http://t/announce"),
+            format!(
+                "http://t/announce?info_hash={}&peer_id=-XX0001-%00%01%02%03abcdefgh&port={}\
+                 &uploaded=0&downloaded=0&left=100&compact=1&key=1234ABCD&event=started\
+                 &numwant=50&ip=10.0.0.1",
+                "%AB".repeat(20),
+                Identity::session().port
+            )
+        );
+
+        let url = request.to_url("http://t/announce?passkey=abc");
+        assert!(url.starts_with("http://t/announce?passkey=abc&info_hash=%AB"));
+        assert_eq!(url.matches('?').count(), 1);
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        assert_eq!(
+            scrape_url("http://example.com/announce").as_deref(),
+            Some("http://example.com/scrape")
+        );
+        assert_eq!(
+            scrape_url("http://example.com/x/announce.php?passkey=a/b").as_deref(),
+            Some("http://example.com/x/scrape.php?passkey=a/b")
+        );
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/announce/x"), None);
+    }
+
+    #[tokio::test]
+    async fn test_scrape_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                socket.read_exact(&mut byte).await.unwrap();
+                request.push(byte[0]);
+            }
+            let request = String::from_utf8(request).unwrap();
+            assert!(request.starts_with(&format!(
+                "GET /scrape?info_hash={}&info_hash={} ",
+                "%01".repeat(20),
+                "%02".repeat(20)
+            )));
+
+            let mut body = b"d5:filesd20:".to_vec();
+            body.extend([1; 20]);
+            body.extend(b"d8:completei5e10:downloadedi10e10:incompletei3eeee");
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(&body).await.unwrap();
+        });
+
+        let mut trackers = Trackers::new(vec![vec![format!("http://127.0.0.1:{port}/announce")]]);
+        let stats = trackers.scrape(&[&[1; 20], &[2; 20]]).await.unwrap();
+        assert_eq!(
+            stats,
+            [
+                Some(ScrapeStats {
+                    complete: 5,
+                    incomplete: 3,
+                    downloaded: 10
+                }),
+                None
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_response_failure() {
+        let err = parse(b"d14:failure reason17:torrent not founde")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, TrackerError::Failure(reason) if reason == "torrent not found"));
+    }
+}
@@ -0,0 +1,404 @@
+//! The UDP tracker protocol (BEP 15).
+//!
+//! Every exchange needs a connection id, obtained with a `connect` request
+//! and valid for a minute. Requests that go unanswered are sent again after
+//! 15 * 2^n seconds, up to n = 8.
+
+use anyhow::{anyhow, Result};
+use reqwest::Url;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, time::timeout_at};
+
+use crate::random::random_u32;
+
+use super::{
+    compact_peers, compact_peers6, AnnounceResponse, Event, ScrapeStats, TrackerError,
+    TrackerRequest,
+};
+
+const PROTOCOL_ID: u64 = 0x0417_2710_1980;
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+// The most info hashes BEP 15 lets a scrape ask about, which keeps the
+// packet within a typical MTU.
+const MAX_SCRAPE_HASHES: usize = 74;
+
+/// Talks to UDP trackers, remembering their connection ids until they
+/// expire.
+#[derive(Debug)]
+pub struct UdpTrackerClient {
+    connections: Mutex<HashMap<SocketAddr, (u64, Instant)>>,
+    timeout: Duration,
+    max_retries: u32,
+}
+
+impl Default for UdpTrackerClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UdpTrackerClient {
+    /// BEP 15 retries up to 8 times, which takes about two hours; giving up
+    /// after 2 (under two minutes) lets the next tracker in the tier get a
+    /// turn.
+    pub fn new() -> Self {
+        Self::with_timeout(Duration::from_secs(15), 2)
+    }
+
+    /// Waits `timeout * 2^n` for the answer to the n-th attempt, giving up
+    /// after `max_retries` retransmissions.
+    pub fn with_timeout(timeout: Duration, max_retries: u32) -> Self {
+        Self {
+            connections: Mutex::new(HashMap::new()),
+            timeout,
+            max_retries,
+        }
+    }
+
+    pub async fn announce(
+        &self,
+        tracker: &str,
+        request: &TrackerRequest,
+    ) -> Result<AnnounceResponse> {
+        let addr = resolve(tracker).await?;
+        let mut body = Vec::with_capacity(82);
+        body.extend(info_hash(&request.info_hash)?);
+        body.extend(request.peer_id);
+        body.extend(request.downloaded.to_be_bytes());
+        body.extend(request.left.to_be_bytes());
+        body.extend(request.uploaded.to_be_bytes());
+        body.extend(request.event.map_or(0, Event::udp_code).to_be_bytes());
+        // An IPv4 address of 0 means the one the packet comes from, and a
+        // `num_want` of -1 as many peers as the tracker likes.
+        let ip = match request.ip {
+            Some(IpAddr::V4(ip)) => ip.octets(),
+            _ => [0; 4],
+        };
+        body.extend(ip);
+        body.extend(request.key.to_be_bytes());
+        let numwant = request
+            .numwant
+            .map_or(-1, |n| i32::try_from(n).unwrap_or(i32::MAX));
+        body.extend(numwant.to_be_bytes());
+        body.extend(request.port.to_be_bytes());
+
+        let response = self.request(addr, ACTION_ANNOUNCE, &body).await?;
+        if response.len() < 12 {
+            return Err(anyhow!("Announce response too short"));
+        }
+        Ok(AnnounceResponse {
+            // Peers come in the address family the tracker was reached over.
+            peers: if addr.is_ipv4() {
+                compact_peers(&response[12..])
+            } else {
+                compact_peers6(&response[12..])
+            },
+            interval: Duration::from_secs(read_u32(&response, 0).into()),
+            min_interval: None,
+            leechers: Some(read_u32(&response, 4)),
+            seeders: Some(read_u32(&response, 8)),
+        })
+    }
+
+    /// Fetches the counts for each of `info_hashes`, in the same order,
+    /// sending as many requests as BEP 15's limit per scrape calls for.
+    pub async fn scrape(&self, tracker: &str, info_hashes: &[&[u8]]) -> Result<Vec<ScrapeStats>> {
+        let addr = resolve(tracker).await?;
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let mut body = Vec::with_capacity(chunk.len() * 20);
+            for hash in chunk {
+                body.extend(info_hash(hash)?);
+            }
+
+            let response = self.request(addr, ACTION_SCRAPE, &body).await?;
+            if response.len() < chunk.len() * 12 {
+                return Err(anyhow!("Scrape response too short"));
+            }
+            stats.extend(
+                response
+                    .chunks_exact(12)
+                    .take(chunk.len())
+                    .map(|counts| ScrapeStats {
+                        complete: read_u32(counts, 0),
+                        downloaded: read_u32(counts, 4),
+                        incomplete: read_u32(counts, 8),
+                    }),
+            );
+        }
+        Ok(stats)
+    }
+
+    /// Sends `body` with `action` to the tracker, connecting first if there
+    /// is no fresh connection id, and returns the response after its action
+    /// and transaction id.
+    async fn request(&self, addr: SocketAddr, action: u32, body: &[u8]) -> Result<Vec<u8>> {
+        let local: SocketAddr = if addr.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+
+        for attempt in 0..=self.max_retries {
+            let timeout = self.timeout * 2u32.pow(attempt);
+            let connection_id = if let Some(id) = self.connection_id(addr) {
+                id
+            } else {
+                let Some(response) =
+                    exchange(&socket, PROTOCOL_ID, ACTION_CONNECT, &[], timeout).await?
+                else {
+                    continue;
+                };
+                if response.len() < 8 {
+                    return Err(anyhow!("Connect response too short"));
+                }
+                let id = u64::from_be_bytes(response[..8].try_into()?);
+                self.connections
+                    .lock()
+                    .expect("connection cache poisoned")
+                    .insert(addr, (id, Instant::now()));
+                id
+            };
+            if let Some(response) = exchange(&socket, connection_id, action, body, timeout).await? {
+                return Ok(response);
+            }
+        }
+        Err(anyhow!("Tracker {addr} did not answer"))
+    }
+
+    fn connection_id(&self, addr: SocketAddr) -> Option<u64> {
+        let mut connections = self.connections.lock().expect("connection cache poisoned");
+        match connections.get(&addr) {
+            Some((id, obtained)) if obtained.elapsed() < CONNECTION_ID_LIFETIME => Some(*id),
+            Some(_) => {
+                connections.remove(&addr);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+/// Sends one packet and waits up to `timeout` for the answer with the same
+/// transaction id, returning `None` if it doesn't come.
+async fn exchange(
+    socket: &UdpSocket,
+    connection_id: u64,
+    action: u32,
+    body: &[u8],
+    timeout: Duration,
+) -> Result<Option<Vec<u8>>> {
+    let transaction_id = random_u32();
+    let mut packet = Vec::with_capacity(16 + body.len());
+    packet.extend(connection_id.to_be_bytes());
+    packet.extend(action.to_be_bytes());
+    packet.extend(transaction_id.to_be_bytes());
+    packet.extend(body);
+    socket.send(&packet).await?;
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut buf = vec![0; 65536];
+    loop {
+        let Ok(len) = timeout_at(deadline, socket.recv(&mut buf)).await else {
+            return Ok(None);
+        };
+        let response = &buf[..len?];
+        // Stray answers to earlier attempts are dropped.
+        if response.len() < 8 || read_u32(response, 4) != transaction_id {
+            continue;
+        }
+        return match read_u32(response, 0) {
+            ACTION_ERROR => Err(TrackerError::Failure(
+                String::from_utf8_lossy(&response[8..]).into_owned(),
+            )
+            .into()),
+            got if got == action => Ok(Some(response[8..].to_vec())),
+            got => Err(anyhow!("Expected action {action} from tracker, got {got}")),
+        };
+    }
+}
+
+/// Looks up the tracker's address, preferring IPv4 when it has both.
+async fn resolve(tracker: &str) -> Result<SocketAddr> {
+    let url = Url::parse(tracker)?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("No host in tracker URL {tracker}"))?;
+    let port = url
+        .port()
+        .ok_or_else(|| anyhow!("No port in tracker URL {tracker}"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+    addrs
+        .iter()
+        .find(|addr| addr.is_ipv4())
+        .or(addrs.first())
+        .copied()
+        .ok_or_else(|| anyhow!("No address for tracker {tracker}"))
+}
+
+fn info_hash(hash: &[u8]) -> Result<[u8; 20]> {
+    hash.try_into()
+        .map_err(|_| anyhow!("Info hash must be 20 bytes, not {}", hash.len()))
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(bytes[at..at + 4].try_into().expect("four bytes"))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    const CONNECTION_ID: u64 = 0x1234_5678_9abc_def0;
+
+    // A UDP tracker stand-in. It ignores the first `drop` packets, counts
+    // connects, answers announces for an all-zero info hash with an error,
+    // and scrapes with counts offset by each hash's first byte.
+    async fn serve(socket: UdpSocket, mut drop: usize, connects: Arc<AtomicUsize>) {
+        let mut buf = [0; 2048];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            if drop > 0 {
+                drop -= 1;
+                continue;
+            }
+            let packet = &buf[..len];
+            let connection_id = u64::from_be_bytes(packet[..8].try_into().unwrap());
+            let action = read_u32(packet, 8);
+            let transaction_id = &packet[12..16];
+            let mut response = Vec::new();
+            match action {
+                ACTION_CONNECT => {
+                    assert_eq!(connection_id, PROTOCOL_ID);
+                    connects.fetch_add(1, Ordering::SeqCst);
+                    response.extend(ACTION_CONNECT.to_be_bytes());
+                    response.extend(transaction_id);
+                    response.extend(CONNECTION_ID.to_be_bytes());
+                }
+                ACTION_ANNOUNCE if packet[16..36] == [0; 20] => {
+                    response.extend(ACTION_ERROR.to_be_bytes());
+                    response.extend(transaction_id);
+                    response.extend(b"unregistered torrent");
+                }
+                ACTION_ANNOUNCE => {
+                    assert_eq!(connection_id, CONNECTION_ID);
+                    assert_eq!(packet.len(), 98);
+                    response.extend(ACTION_ANNOUNCE.to_be_bytes());
+                    response.extend(transaction_id);
+                    for n in [1800u32, 3, 5] {
+                        response.extend(n.to_be_bytes());
+                    }
+                    response.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+                }
+                ACTION_SCRAPE => {
+                    response.extend(ACTION_SCRAPE.to_be_bytes());
+                    response.extend(transaction_id);
+                    assert!(packet.len() - 16 <= MAX_SCRAPE_HASHES * 20);
+                    for hash in packet[16..].chunks(20) {
+                        for n in [4u32, 9, 2] {
+                            response.extend((n + u32::from(hash[0])).to_be_bytes());
+                        }
+                    }
+                }
+                _ => unreachable!(),
+            }
+            socket.send_to(&response, from).await.unwrap();
+        }
+    }
+
+    async fn start(drop: usize) -> (String, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let connects = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(serve(socket, drop, Arc::clone(&connects)));
+        (url, connects)
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_scrape() {
+        let (url, connects) = start(0).await;
+        let client = UdpTrackerClient::with_timeout(Duration::from_millis(50), 2);
+        let request = TrackerRequest::new(vec![1; 20], 100);
+
+        let response = client.announce(&url, &request).await.unwrap();
+        assert_eq!(response.interval, Duration::from_secs(30 * 60));
+        assert_eq!((response.leechers, response.seeders), (Some(3), Some(5)));
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(response.peers[0].to_string(), "10.0.0.1:6881");
+
+        let stats = client.scrape(&url, &[&[1; 20], &[2; 20]]).await.unwrap();
+        assert_eq!(
+            stats,
+            [
+                ScrapeStats {
+                    complete: 5,
+                    incomplete: 3,
+                    downloaded: 10
+                },
+                ScrapeStats {
+                    complete: 6,
+                    incomplete: 4,
+                    downloaded: 11
+                },
+            ]
+        );
+        // The connection id is reused.
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+        let err = client
+            .announce(&url, &TrackerRequest::new(vec![0; 20], 100))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TrackerError>(),
+            Some(TrackerError::Failure(reason)) if reason == "unregistered torrent"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_scrape_many() {
+        let (url, connects) = start(0).await;
+        let client = UdpTrackerClient::with_timeout(Duration::from_millis(50), 2);
+        let hashes: Vec<[u8; 20]> = (1..=200).map(|n| [n; 20]).collect();
+        let hashes: Vec<&[u8]> = hashes.iter().map(|hash| &hash[..]).collect();
+
+        let stats = client.scrape(&url, &hashes).await.unwrap();
+        let complete: Vec<u32> = stats.iter().map(|stats| stats.complete).collect();
+        assert_eq!(complete, (5..=204).collect::<Vec<_>>());
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retransmit() {
+        // The first connect goes unanswered.
+        let (url, _) = start(1).await;
+        let client = UdpTrackerClient::with_timeout(Duration::from_millis(20), 3);
+        let request = TrackerRequest::new(vec![1; 20], 100);
+        let response = client.announce(&url, &request).await.unwrap();
+        assert_eq!(response.peers.len(), 1);
+
+        let (url, _) = start(usize::MAX).await;
+        let client = UdpTrackerClient::with_timeout(Duration::from_millis(5), 2);
+        assert!(client.announce(&url, &request).await.is_err());
+    }
+}
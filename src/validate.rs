@@ -0,0 +1,247 @@
+//! Sanity checks for metainfo that parses but would be unsafe or impossible
+//! to download.
+
+use crate::{
+    bencode::BencodeValue,
+    torrent::{merkle_root, piece_layer_pad, Layout, MetaVersion, Torrent, V2File},
+};
+use std::path::{Component, Path};
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Problem {
+    #[error("piece length is zero")]
+    ZeroPieceLength,
+    #[error("piece length {0} is not a power of two")]
+    PieceLengthNotPowerOfTwo(u32),
+    #[error("`pieces` is {0} bytes long, which is not a multiple of 20")]
+    PiecesNotMultipleOf20(usize),
+    #[error("{actual} piece hashes for {expected} pieces of content")]
+    PieceCount { expected: u64, actual: u64 },
+    #[error("the name is empty")]
+    EmptyName,
+    #[error("path {0:?} escapes the download directory")]
+    PathTraversal(String),
+    #[error("name {0:?} is not valid UTF-8")]
+    NonUtf8Name(String),
+    #[error("{0}: missing or invalid piece layer")]
+    BadPieceLayer(String),
+}
+
+impl Problem {
+    /// Whether the torrent can't be downloaded safely. A piece length that
+    /// isn't a power of two is against the spec but works.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, Problem::PieceLengthNotPowerOfTwo(_))
+    }
+}
+
+impl Torrent {
+    /// Lists everything wrong with the torrent, fatal or not.
+    pub fn validate(&self) -> Vec<Problem> {
+        let info = &*self.info;
+        let mut problems = Vec::new();
+
+        if info.piece_length == 0 {
+            problems.push(Problem::ZeroPieceLength);
+        } else if !info.piece_length.is_power_of_two() {
+            problems.push(Problem::PieceLengthNotPowerOfTwo(info.piece_length));
+        }
+
+        if info.version() != MetaVersion::V2 {
+            if info.pieces.len() % 20 != 0 {
+                problems.push(Problem::PiecesNotMultipleOf20(info.pieces.len()));
+            } else if info.piece_length != 0 {
+                let expected = self.length().div_ceil(u64::from(info.piece_length));
+                let actual = info.pieces.len() as u64 / 20;
+                if expected != actual {
+                    problems.push(Problem::PieceCount { expected, actual });
+                }
+            }
+        }
+
+        if info.name.is_empty() {
+            problems.push(Problem::EmptyName);
+        } else if !is_safe_component(&info.name) {
+            problems.push(Problem::PathTraversal(info.name.clone()));
+        }
+        if let Some(Layout::MultiFile { files }) = &info.layout {
+            for file in files {
+                if file.path.is_empty() || !file.path.iter().all(|c| is_safe_component(c)) {
+                    problems.push(Problem::PathTraversal(file.path.join("/")));
+                }
+            }
+        }
+        for (path, file) in info.v2_files() {
+            let safe = path.components().all(
+                |c| matches!(c, Component::Normal(c) if c.to_str().is_some_and(is_safe_component)),
+            );
+            if !safe {
+                problems.push(Problem::PathTraversal(path.display().to_string()));
+            }
+            if info.piece_length != 0 && !self.piece_layer_matches(file, info.piece_length) {
+                problems.push(Problem::BadPieceLayer(path.display().to_string()));
+            }
+        }
+
+        problems
+    }
+
+    /// Files larger than a piece need a piece layer hashing up to their
+    /// `pieces root`; other non-empty files just need the root.
+    fn piece_layer_matches(&self, file: &V2File, piece_length: u32) -> bool {
+        let Some(root) = &file.pieces_root else {
+            return file.length == 0;
+        };
+        if root.len() != 32 {
+            return false;
+        }
+        if file.length <= u64::from(piece_length) {
+            return true;
+        }
+        let Some(layer) = self.piece_layer(file) else {
+            return false;
+        };
+        layer.len() as u64 == file.length.div_ceil(u64::from(piece_length))
+            && merkle_root(&layer, piece_layer_pad(piece_length))[..] == root[..]
+    }
+}
+
+/// Checks the names in an info dictionary for invalid UTF-8, which stops it
+/// from parsing as a [`Torrent`] at all.
+pub fn check_names(info: &BencodeValue) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut check = |name: &[u8]| {
+        if std::str::from_utf8(name).is_err() {
+            problems.push(Problem::NonUtf8Name(
+                String::from_utf8_lossy(name).into_owned(),
+            ));
+        }
+    };
+
+    if let Some(name) = info.get("name").and_then(BencodeValue::as_bytes) {
+        check(name);
+    }
+    let files = info.get("files").and_then(BencodeValue::as_list);
+    for file in files.into_iter().flatten() {
+        let path = file.get("path").and_then(BencodeValue::as_list);
+        for component in path
+            .into_iter()
+            .flatten()
+            .filter_map(BencodeValue::as_bytes)
+        {
+            check(component);
+        }
+    }
+    if let Some(tree) = info.get("file tree") {
+        check_tree_names(tree, &mut check);
+    }
+    problems
+}
+
+fn check_tree_names(tree: &BencodeValue, check: &mut impl FnMut(&[u8])) {
+    for (name, node) in tree.as_dict().into_iter().flatten() {
+        // The empty key holds a file's details rather than a child.
+        if !name.is_empty() {
+            check(name);
+            check_tree_names(node, check);
+        }
+    }
+}
+
+/// A single, ordinary path component: not empty, not `.` or `..`, and free
+/// of separators.
+fn is_safe_component(name: &str) -> bool {
+    !name.contains(['/', '\\'])
+        && matches!(
+            &Path::new(name).components().collect::<Vec<_>>()[..],
+            [Component::Normal(_)]
+        )
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mini_serde_bencode::{from_bytes, from_str};
+
+    fn parse(info: &str) -> Torrent {
+        from_str::<Torrent>(&format!("d8:announce9:http://a/4:info{info}e")).unwrap()
+    }
+
+    #[test]
+    fn test_valid() {
+        let torrent = parse("d6:lengthi40e4:name4:spam12:piece lengthi32e6:pieces40:0123456789abcdef01230123456789abcdef0123e");
+        assert_eq!(torrent.validate(), []);
+    }
+
+    #[test]
+    fn test_piece_problems() {
+        let torrent =
+            parse("d6:lengthi40e4:name4:spam12:piece lengthi20e6:pieces22:0123456789abcdef012345e");
+        assert_eq!(
+            torrent.validate(),
+            [
+                Problem::PieceLengthNotPowerOfTwo(20),
+                Problem::PiecesNotMultipleOf20(22),
+            ]
+        );
+        assert!(!torrent.validate()[0].is_fatal());
+
+        let torrent =
+            parse("d6:lengthi40e4:name4:spam12:piece lengthi16e6:pieces20:0123456789abcdef0123e");
+        assert_eq!(
+            torrent.validate(),
+            [Problem::PieceCount {
+                expected: 3,
+                actual: 1
+            }]
+        );
+
+        let torrent = parse("d6:lengthi40e4:name4:spam12:piece lengthi0e6:pieces0:e");
+        assert_eq!(torrent.validate(), [Problem::ZeroPieceLength]);
+    }
+
+    #[test]
+    fn test_path_problems() {
+        let torrent = parse("d5:filesld6:lengthi5e4:pathl2:..5:b.txteed6:lengthi7e4:pathl5:c.txteee4:name0:12:piece lengthi16e6:pieces20:0123456789abcdef0123e");
+        assert_eq!(
+            torrent.validate(),
+            [
+                Problem::EmptyName,
+                Problem::PathTraversal("../b.txt".to_string())
+            ]
+        );
+
+        let torrent =
+            parse("d6:lengthi5e4:name7:../spam12:piece lengthi16e6:pieces20:0123456789abcdef0123e");
+        assert_eq!(
+            torrent.validate(),
+            [Problem::PathTraversal("../spam".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_check_names() {
+        let info = b"d5:filesld6:lengthi5e4:pathl2:\xffaeee4:name4:spame";
+        let info = from_bytes::<BencodeValue>(info).unwrap();
+        assert_eq!(
+            check_names(&info),
+            [Problem::NonUtf8Name("\u{fffd}a".to_string())]
+        );
+
+        let info = BencodeValue::dict()
+            .entry("name", "spam")
+            .entry(
+                "file tree",
+                BencodeValue::dict()
+                    .entry(b"\xfe".to_vec(), BencodeValue::dict().build())
+                    .build(),
+            )
+            .build();
+        assert_eq!(
+            check_names(&info),
+            [Problem::NonUtf8Name("\u{fffd}".to_string())]
+        );
+    }
+}
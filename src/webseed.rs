@@ -0,0 +1,239 @@
+//! Downloading pieces from web seeds: plain HTTP servers that either host
+//! the torrent's files (BEP 19, `url-list`) or hand out pieces by index
+//! (BEP 17, `httpseeds`).
+
+use anyhow::{anyhow, Result};
+use bittorrent_starter_rust::torrent::Torrent;
+use reqwest::{header::RANGE, Client, StatusCode};
+use std::{fmt::Display, path::Path, sync::Arc};
+
+use crate::{peer::check_piece, tracker::percent_encode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// BEP 19: the URL points at the file, or the directory holding it.
+    UrlList,
+    /// BEP 17: the URL takes `info_hash` and `piece` query parameters.
+    HttpSeed,
+}
+
+#[derive(Debug, Clone)]
+pub struct WebSeed {
+    kind: Kind,
+    url: String,
+    client: Client,
+    torrent: Arc<Torrent>,
+}
+
+impl Display for WebSeed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
+impl WebSeed {
+    /// The web seeds listed in the torrent, sharing one HTTP client.
+    pub fn all(torrent: &Arc<Torrent>) -> Vec<WebSeed> {
+        let client = Client::new();
+        let url_list = torrent.url_list.iter().map(|url| (Kind::UrlList, url));
+        let http_seeds = torrent.http_seeds.iter().map(|url| (Kind::HttpSeed, url));
+        url_list
+            .chain(http_seeds)
+            .map(|(kind, url)| WebSeed {
+                kind,
+                url: url.clone(),
+                client: client.clone(),
+                torrent: Arc::clone(torrent),
+            })
+            .collect()
+    }
+
+    pub async fn download_piece(&self, piece_index: u32) -> Result<Vec<u8>> {
+        let piece = match self.kind {
+            Kind::UrlList => self.fetch_ranges(piece_index).await?,
+            Kind::HttpSeed => self.fetch_piece(piece_index).await?,
+        };
+
+        if !check_piece(&piece, self.torrent.piece_hash(piece_index)?) {
+            Err(anyhow!("Piece hash does not match"))?;
+        }
+
+        Ok(piece)
+    }
+
+    /// Reads the piece out of the files it spans, one Range request per
+    /// file.
+    async fn fetch_ranges(&self, piece_index: u32) -> Result<Vec<u8>> {
+        let mut piece = Vec::with_capacity(self.torrent.piece_len(piece_index)? as usize);
+        for slice in self.torrent.files_for_piece(piece_index)? {
+            // Pad files are zeros by definition and web seeds don't host them.
+            if slice.padding {
+                piece.resize(piece.len() + usize::try_from(slice.length)?, 0);
+                continue;
+            }
+            let url = self.file_url(&slice.path);
+            piece.extend(
+                self.fetch_range(&url, slice.offset, slice.offset + slice.length)
+                    .await?,
+            );
+        }
+        Ok(piece)
+    }
+
+    /// Fetches bytes `from..to` of the file at `url`. Servers that ignore
+    /// the Range header send the whole file, which still works.
+    async fn fetch_range(&self, url: &str, from: u64, to: u64) -> Result<Vec<u8>> {
+        let response = self
+            .client
+            .get(url)
+            .header(RANGE, format!("bytes={from}-{}", to - 1))
+            .send()
+            .await?
+            .error_for_status()?;
+        let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+        let body = response.bytes().await?;
+
+        let len = usize::try_from(to - from)?;
+        let data = if partial {
+            &body[..]
+        } else {
+            body.get(usize::try_from(from)?..).unwrap_or_default()
+        };
+        if data.len() < len || (partial && data.len() != len) {
+            return Err(anyhow!(
+                "Expected {len} bytes from {url}, got {}",
+                data.len()
+            ));
+        }
+        Ok(data[..len].to_vec())
+    }
+
+    /// The URL of a file of the content. A URL ending in `/` names the
+    /// directory the torrent's content sits in; otherwise, for single-file
+    /// torrents, it is the file itself.
+    fn file_url(&self, path: &Path) -> String {
+        if self.torrent.info.is_single_file() && !self.url.ends_with('/') {
+            return self.url.clone();
+        }
+        let mut url = self.url.clone();
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        let components: Vec<String> = path
+            .iter()
+            .map(|component| percent_encode(component.to_string_lossy().as_bytes()))
+            .collect();
+        url + &components.join("/")
+    }
+
+    async fn fetch_piece(&self, piece_index: u32) -> Result<Vec<u8>> {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let url = format!(
+            "{}{separator}info_hash={}&piece={piece_index}",
+            self.url,
+            percent_encode(&self.torrent.info_hash())
+        );
+        let response = self.client.get(&url).send().await?;
+        // BEP 17 seeds answer 503 with a number of seconds to wait when busy.
+        if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+            let retry = response.text().await.unwrap_or_default();
+            return Err(anyhow!("Web seed busy, retry in {} seconds", retry.trim()));
+        }
+        Ok(response.error_for_status()?.bytes().await?.to_vec())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bittorrent_starter_rust::create::TorrentBuilder;
+    use std::{collections::HashMap, fs};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // A minimal HTTP server: serves `files` by path, honoring `bytes=a-b`
+    // ranges, and the pieces of `pieces` to BEP 17 requests.
+    async fn serve(listener: TcpListener, files: HashMap<String, Vec<u8>>, pieces: Vec<Vec<u8>>) {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                if socket.read(&mut byte).await.unwrap() == 0 {
+                    break;
+                }
+                request.push(byte[0]);
+            }
+            let request = String::from_utf8(request).unwrap().to_lowercase();
+            let target = request.split(' ').nth(1).unwrap().to_string();
+            let range = request
+                .lines()
+                .find_map(|line| line.strip_prefix("range: bytes="))
+                .and_then(|range| range.split_once('-'))
+                .map(|(from, to)| (from.parse::<usize>().unwrap(), to.parse::<usize>().unwrap()));
+
+            let (status, body) = if let Some((_, query)) = target.split_once('?') {
+                let piece = query.split('&').find_map(|p| p.strip_prefix("piece="));
+                let piece: usize = piece.unwrap().parse().unwrap();
+                ("200 OK", pieces[piece].clone())
+            } else {
+                match (files.get(&target), range) {
+                    (Some(file), Some((from, to))) => {
+                        ("206 Partial Content", file[from..=to].to_vec())
+                    }
+                    (Some(file), None) => ("200 OK", file.clone()),
+                    (None, _) => ("404 Not Found", Vec::new()),
+                }
+            };
+            let head = format!(
+                "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(&body).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_piece() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("con tent");
+        fs::create_dir_all(root.join("b")).unwrap();
+        let a: Vec<u8> = (0..=250).cycle().take(10_000).collect();
+        let c: Vec<u8> = (0..=255).rev().cycle().take(30_000).collect();
+        fs::write(root.join("a"), &a).unwrap();
+        fs::write(root.join("b").join("c"), &c).unwrap();
+        let content = [&a[..], &c[..]].concat();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let files = HashMap::from([
+            ("/seed/con%20tent/a".to_string(), a.clone()),
+            ("/seed/con%20tent/b/c".to_string(), c.clone()),
+        ]);
+        let pieces = content.chunks(16 * 1024).map(<[u8]>::to_vec).collect();
+        tokio::spawn(serve(listener, files, pieces));
+
+        let mut torrent = TorrentBuilder::new(&root)
+            .tracker("http://tracker/")
+            .web_seed(format!("http://127.0.0.1:{port}/seed"))
+            .piece_length(16 * 1024)
+            .build()
+            .unwrap();
+        torrent.http_seeds = vec![format!("http://127.0.0.1:{port}/bep17")];
+        let seeds = WebSeed::all(&Arc::new(torrent));
+        assert_eq!(seeds.len(), 2);
+
+        for seed in &seeds {
+            // Piece 0 lies within `a` and `b/c`; piece 2 is the short last one.
+            for (index, expected) in (0..).zip(content.chunks(16 * 1024)) {
+                let piece = seed.download_piece(index).await.unwrap();
+                assert_eq!(piece, expected, "{seed} piece {index}");
+            }
+        }
+    }
+}